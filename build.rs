@@ -1,60 +1,98 @@
 use std::{env, path::PathBuf};
 
+// fuse2 and fuse3 have incompatible headers and ABIs, so the library we link
+// against has to agree with the FUSE_USE_VERSION that wrapper.h picks.
+// Mixing them up gets you an outdated version warning message
+// and (*fuse_get_context()).private_data gets mangled.
+#[cfg(all(feature = "fuse3", not(feature = "pure_rust")))]
+const LIBRARY: &str = "fuse3";
+#[cfg(not(any(feature = "fuse3", feature = "pure_rust")))]
+const LIBRARY: &str = "fuse";
+
 fn main() {
-    // When building with fuse3, we get an outdated version warning message
-    // and (*fuse_get_conext()).private_data gets mangled
-    println!("cargo:rustc-link-lib=fuse");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("bindings.rs");
+
+    // Nothing to link or generate, the bindings are checked in
+    #[cfg(feature = "pure_rust")]
+    {
+        println!("cargo:rerun-if-changed=kernel_bindings.rs");
+        std::fs::copy("kernel_bindings.rs", &out).expect("Couldn't copy kernel_bindings.rs");
+    }
+    #[cfg(not(feature = "pure_rust"))]
+    generate_bindings(&out);
+
+    #[cfg(feature = "auto")]
+    add_macros(&out);
+}
+
+#[cfg(not(feature = "pure_rust"))]
+fn generate_bindings(out: &std::path::Path) {
+    println!("cargo:rustc-link-lib={LIBRARY}");
+
+    let library = pkg_config::probe_library(LIBRARY)
+        .unwrap_or_else(|_| panic!("pkg-config failed to find {LIBRARY}"));
+
+    #[allow(unused_mut)]
+    let mut clang_args: Vec<String> = library
+        .include_paths
+        .iter()
+        .map(|path| format!("-I{}", path.to_string_lossy()))
+        .collect();
+
+    // Tells wrapper.h to ask for the fuse3 API
+    #[cfg(feature = "fuse3")]
+    clang_args.push("-DFUSE_SYS_FUSE3".to_owned());
 
-    let library = pkg_config::probe_library("fuse").expect("pkg-config failed to find fuse");
     let bindings = bindgen::Builder::default()
-        .clang_args(
-            library
-                .include_paths
-                .iter()
-                .map(|path| format!("-I{}", path.to_string_lossy())),
-        )
+        .clang_args(clang_args)
         .header("wrapper.h")
         .derive_default(true)
         .generate()
         .expect("Could not generate bindings");
 
-    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("bindings.rs");
     bindings
-        .write_to_file(&out)
+        .write_to_file(out)
         .expect("Couldn't write bindings!");
+}
 
-    #[cfg(feature = "auto")]
+#[cfg(feature = "auto")]
+fn add_macros(out: &std::path::Path) {
+    use std::fs;
+
+    let mut bindings_raw = fs::read_to_string(out).unwrap();
+
+    let operations_loc = bindings_raw
+        .find("pub struct fuse_operations")
+        .expect("Could not find struct fuse_operations");
+
+    // The attributes on the fuse_operations macro correspond
+    // to the fuse operations that are blacklisted
+    // for versioning issues. In theory these operations
+    // shouldn't show up on the struct at all, but whatever
+    // I'm not mad or anything like that's totally fine I'm fine.
+    let blacklisted = ["getdir", "utime"];
+
+    // macOS makes me question my reality.
+    #[cfg(target_os = "macos")]
+    let blacklisted = [&blacklisted[..], &["reserved00", "reserved01"]].concat();
+
+    bindings_raw.insert_str(
+        operations_loc,
+        &format!(
+            "#[filesystem_macro::fuse_operations[{}]]",
+            blacklisted.join(", ")
+        ),
+    );
+
+    // There's no low level api without libfuse
+    #[cfg(not(feature = "pure_rust"))]
     {
-        use std::fs;
-
-        let mut bindings_raw = fs::read_to_string(&out).unwrap();
-
-        let operations_loc = bindings_raw
-            .find("pub struct fuse_operations")
-            .expect("Could not find struct fuse_operations");
-
-        // The attributes on the fuse_operations macro correspond
-        // to the fuse operations that are blacklisted
-        // for versioning issues. In theory these operations
-        // shouldn't show up on the struct at all, but whatever
-        // I'm not mad or anything like that's totally fine I'm fine.
-        #[allow(unused_mut)]
-        let mut blacklisted = vec!["getdir, utime"];
-
-        // macOS makes me question my reality.
-        #[cfg(target_os = "macos")]
-        {
-            blacklisted.extend(["reserved00, reserved01"]);
-        }
-
-        bindings_raw.insert_str(
-            operations_loc,
-            &format!(
-                "#[filesystem_macro::fuse_operations[{}]]",
-                blacklisted.join(", ")
-            ),
-        );
-
-        fs::write(out, bindings_raw).unwrap();
+        let lowlevel_ops_loc = bindings_raw
+            .find("pub struct fuse_lowlevel_ops")
+            .expect("Could not find struct fuse_lowlevel_ops");
+
+        bindings_raw.insert_str(lowlevel_ops_loc, "#[filesystem_macro::fuse_lowlevel_ops]");
     }
+
+    fs::write(out, bindings_raw).unwrap();
 }
@@ -2,179 +2,202 @@ use clap::StructOpt;
 use fuse_sys::prelude::*;
 use nix::sys::stat as nixstat;
 use std::{
-    env,
     fs::*,
-    io::{ErrorKind, Result},
-    os::{raw::c_void, unix::fs::*},
+    io::{self, ErrorKind},
+    os::unix::fs::*,
+    path::{Path, PathBuf},
 };
 
+type Result<T> = std::result::Result<T, Errno>;
+
 struct Passthrough {
-    root: String,
+    root: PathBuf,
 }
 
 impl Passthrough {
-    fn new(root: String) -> Self {
-        Self { root }
+    fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
     }
 
-    fn source(&self, relative: &str) -> String {
-        format!("{}/{relative}", self.root)
+    fn source(&self, relative: &Path) -> PathBuf {
+        self.root
+            .join(relative.strip_prefix("/").unwrap_or(relative))
     }
 }
 
+// custom_flags leaves the access mode alone, so it has to be picked out by hand
+fn open_options(info: Option<&mut fuse_file_info>) -> OpenOptions {
+    let flags = info.map(|info| info.flags).unwrap_or(libc::O_RDONLY);
+
+    let mut options = OpenOptions::new();
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => options.write(true),
+        libc::O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    options.custom_flags(flags);
+    options
+}
+
+#[filesystem]
 impl UnthreadedFileSystem for Passthrough {
-    fn chmod(&mut self, path: &str, mode: mode_t) -> Result<i32> {
-        set_permissions(self.source(path), Permissions::from_mode(mode.into())).map(|_| 0)
+    type FileHandle = File;
+
+    fn chmod(&mut self, _req: &Request, path: &Path, mode: mode_t) -> Result<()> {
+        set_permissions(self.source(path), Permissions::from_mode(mode.into()))?;
+        Ok(())
     }
 
     fn create(
         &mut self,
-        path: &str,
+        _req: &Request,
+        path: &Path,
         mode: mode_t,
         info: Option<&mut fuse_file_info>,
-    ) -> Result<i32> {
-        let mut options = OpenOptions::new();
-        if let Some(info) = info {
-            options.custom_flags(info.flags);
-        }
-
-        options
+    ) -> Result<OpenReply<File>> {
+        let f = open_options(info)
             .create(true)
-            .append(true)
             .mode(mode.into())
-            .open(self.source(path))
-            .map(|_| 0)
+            .open(self.source(path))?;
+        Ok(f.into())
     }
 
     fn fsync(
         &mut self,
-        _path: &str,
-        _datasync: i32,
-        _info: Option<&mut fuse_file_info>,
-    ) -> Result<i32> {
-        Ok(0)
+        _req: &Request,
+        _path: &Path,
+        datasync: i32,
+        f: Option<&File>,
+    ) -> Result<()> {
+        if let Some(f) = f {
+            if datasync != 0 {
+                f.sync_data()?;
+            } else {
+                f.sync_all()?;
+            }
+        }
+        Ok(())
     }
 
-    fn getattr(&mut self, path: &str, stat: Option<&mut stat>) -> Result<i32> {
-        let path: &str = &self.source(path);
-        *stat.unwrap() = unsafe { std::mem::transmute(nixstat::stat(path)?) };
-
-        Ok(0)
+    fn getattr(&mut self, _req: &Request, path: &Path) -> Result<FileAttr> {
+        Ok(symlink_metadata(self.source(path))?.into())
     }
 
-    fn mkdir(&mut self, path: &str, mode: mode_t) -> Result<i32> {
+    fn mkdir(&mut self, _req: &Request, path: &Path, mode: mode_t) -> Result<()> {
         let path = self.source(path);
         create_dir(&path)?;
-        set_permissions(path, Permissions::from_mode(mode.into())).map(|_| 0)
+        set_permissions(path, Permissions::from_mode(mode.into()))?;
+        Ok(())
     }
 
-    fn mknod(&mut self, path: &str, mode: mode_t, dev: dev_t) -> Result<i32> {
-        let path: &str = &self.source(path);
+    fn mknod(&mut self, _req: &Request, path: &Path, mode: mode_t, dev: dev_t) -> Result<()> {
+        let path = self.source(path);
         nixstat::mknod(
-            path,
+            &path,
             nixstat::SFlag::from_bits_truncate(mode),
             nixstat::Mode::from_bits_truncate(mode),
             dev,
-        )?;
-        Ok(0)
+        )
+        .map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    fn open(
+        &mut self,
+        _req: &Request,
+        path: &Path,
+        info: Option<&mut fuse_file_info>,
+    ) -> Result<OpenReply<File>> {
+        Ok(open_options(info).open(self.source(path))?.into())
     }
 
     fn read(
         &mut self,
-        path: &str,
+        _req: &Request,
+        _path: &Path,
         buf: &mut [u8],
         off: off_t,
-        info: Option<&mut fuse_file_info>,
-    ) -> Result<i32> {
-        let mut options = OpenOptions::new();
-        if let Some(info) = info {
-            options.custom_flags(info.flags);
-        }
-
-        let f = options.read(true).open(self.source(path))?;
-        f.read_at(buf, off as u64).map(|n| n as i32)
+        f: Option<&File>,
+    ) -> Result<usize> {
+        let f = f.ok_or(Errno::EBADF)?;
+        Ok(f.read_at(buf, off as u64)?)
     }
 
     fn readdir(
         &mut self,
-        path: &str,
-        buf: Option<&mut c_void>,
-        filler: impl Fn(Option<&mut std::ffi::c_void>, &str, &stat, off_t) -> i32,
-        _off: off_t,
-        _info: Option<&mut fuse_file_info>,
-    ) -> Result<i32> {
-        let buf = match buf {
-            Some(buf) => buf,
-            None => return Ok(0),
-        };
-
-        for entry in read_dir(self.source(path))? {
+        _req: &Request,
+        path: &Path,
+        filler: &mut DirFiller,
+        off: off_t,
+        _dir: Option<&File>,
+    ) -> Result<()> {
+        // Entries are numbered from 1, so an offset of n picks up after the nth one
+        for (i, entry) in read_dir(self.source(path))?.enumerate().skip(off as usize) {
             let entry = entry?;
+            let attr = entry.metadata()?.into();
 
-            let stat = stat {
-                st_ino: entry.ino(),
-                ..Default::default()
-            };
-
-            if filler(Some(buf), entry.file_name().to_str().unwrap(), &stat, 0) != 0 {
+            if filler.add(&entry.file_name(), &attr, i as off_t + 1) {
                 break;
             }
         }
 
-        Ok(0)
+        Ok(())
     }
 
-    fn readlink(&mut self, path: &str, buf: &mut [u8]) -> Result<i32> {
-        if buf.len() == 0 {
-            return Ok(0);
-        }
-
-        let link_buf = read_link(self.source(path))?;
-        let link = link_buf.to_str().unwrap().as_bytes();
-
-        let length = buf.len().min(link.len());
-        (&mut buf[..length]).copy_from_slice(&link[..length]);
-
-        let null = length.min(buf.len() - 1);
-        buf[null] = 0;
-
-        Ok(0)
+    fn readlink(&mut self, _req: &Request, path: &Path) -> Result<PathBuf> {
+        Ok(read_link(self.source(path))?)
     }
 
-    fn rename(&mut self, old: &str, new: &str) -> Result<i32> {
-        rename(self.source(old), self.source(new)).map(|_| 0)
+    fn rename(&mut self, _req: &Request, old: &Path, new: &Path) -> Result<()> {
+        rename(self.source(old), self.source(new))?;
+        Ok(())
     }
 
-    fn rmdir(&mut self, path: &str) -> Result<i32> {
-        remove_dir(self.source(path)).map(|_| 0)
+    fn rmdir(&mut self, _req: &Request, path: &Path) -> Result<()> {
+        remove_dir(self.source(path))?;
+        Ok(())
     }
 
-    fn truncate(&mut self, path: &str, size: off_t) -> Result<i32> {
+    fn truncate(&mut self, _req: &Request, path: &Path, size: off_t) -> Result<()> {
         let f = OpenOptions::new().write(true).open(self.source(path))?;
-        f.set_len(size as u64).map(|_| 0)
+        f.set_len(size as u64)?;
+        Ok(())
     }
 
-    fn unlink(&mut self, path: &str) -> Result<i32> {
-        remove_file(self.source(path)).map(|_| 0)
+    fn unlink(&mut self, _req: &Request, path: &Path) -> Result<()> {
+        remove_file(self.source(path))?;
+        Ok(())
+    }
+
+    fn utimens(
+        &mut self,
+        _req: &Request,
+        path: &Path,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<()> {
+        let mut times = FileTimes::new();
+        if let Some(atime) = atime {
+            times = times.set_accessed(atime.resolve());
+        }
+        if let Some(mtime) = mtime {
+            times = times.set_modified(mtime.resolve());
+        }
+
+        File::open(self.source(path))?.set_times(times)?;
+        Ok(())
     }
 
     fn write(
         &mut self,
-        path: &str,
+        _req: &Request,
+        _path: &Path,
         buf: &[u8],
         off: off_t,
-        info: Option<&mut fuse_file_info>,
-    ) -> Result<i32> {
-        let mut options = OpenOptions::new();
-        if let Some(info) = info {
-            options.custom_flags(info.flags);
-        }
-
-        options
-            .write(true)
-            .open(self.source(path))?
-            .write_at(buf, off as u64)
-            .map(|n| n as i32)
+        f: Option<&File>,
+    ) -> Result<usize> {
+        let f = f.ok_or(Errno::EBADF)?;
+        Ok(f.write_at(buf, off as u64)?)
     }
 }
 
@@ -192,13 +215,14 @@ struct Args {
 }
 
 fn main() {
-    let bin = env::args().next().unwrap();
     let Args { mount, data, debug } = Args::parse();
 
-    let mut fuse_args: Vec<&str> = vec![&bin, &mount, "-f", "-s"];
-    if debug {
-        fuse_args.push("-d");
-    }
+    let options = MountOptions::builder(&mount)
+        .fsname(&data)
+        .subtype("passthrough")
+        .debug(debug)
+        .build()
+        .unwrap();
 
     match read_dir(&mount) {
         Err(e) if e.kind() == ErrorKind::NotFound => create_dir(&mount).unwrap(),
@@ -214,5 +238,5 @@ fn main() {
     }
 
     println!("Mounting {mount} as mirror of {data}...");
-    Passthrough::new(data.to_owned()).run(&fuse_args).unwrap();
+    Passthrough::new(data.to_owned()).run(&options).unwrap();
 }
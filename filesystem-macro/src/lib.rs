@@ -1,3 +1,5 @@
+mod lowlevel;
+
 use std::collections::HashSet;
 
 use proc_macro::TokenStream;
@@ -8,8 +10,8 @@ use syn::{
     parse_macro_input,
     punctuated::Punctuated,
     token::{Comma, Semi},
-    BareFnArg, Expr, Fields, GenericArgument, Ident, ItemStruct, PathArguments, ReturnType, Stmt,
-    Type, TypeBareFn, TypePtr,
+    BareFnArg, Expr, Fields, GenericArgument, Ident, ImplItem, ItemImpl, ItemStruct,
+    PathArguments, ReturnType, Stmt, Type, TypeBareFn, TypePtr,
 };
 
 const IDENT_CHARS: &'static str = "_qwertyuiopasdfghjklzxcvbnmQWERTYUIOPASDFGHJKLZXCVBNM";
@@ -30,9 +32,190 @@ fn is_ident(ty: &Type, ident: &str) -> bool {
     matches!(ty, Type::Path(path) if path.path.segments.last().unwrap().ident.to_string() == ident)
 }
 
+// Renames the arguments that get converted into something other than what their type says.
+// The high level ops mostly don't name their arguments, so the attribute's name would
+// otherwise end up a Path and setxattr's flags a bare c_int. The lock ops' commands get
+// names nothing else uses, so they can't be mistaken for some other c_int, and ioctl's
+// data needs its command to know how big it is.
+fn name_args(name: &Ident, mut inputs: Punctuated<BareFnArg, Comma>) -> Punctuated<BareFnArg, Comma> {
+    let mut rename = |i: usize, new: &str| {
+        if let Some(BareFnArg { name: Some((ident, _)), .. }) = inputs.iter_mut().nth(i) {
+            *ident = Ident::new(new, ident.span());
+        }
+    };
+
+    match name.to_string().as_str() {
+        "getxattr" | "removexattr" => rename(1, "name"),
+        "setxattr" => {
+            rename(1, "name");
+            rename(4, "xattr_flags");
+        }
+        "lock" => rename(2, "lock_cmd"),
+        "flock" => rename(2, "flock_op"),
+        "ioctl" => {
+            rename(1, "ioctl_cmd");
+            rename(5, "ioctl_data");
+        }
+        _ => {}
+    }
+    inputs
+}
+
+struct Returns {
+    ty: TokenStream2,
+    // Arguments that only exist for the return value, which the handler doesn't get to see
+    out_params: Vec<usize>,
+    // Turns the handler's value (bound to the ident passed to `returns`) into what libfuse wants
+    write: TokenStream2,
+}
+
+// What each operation hands back, and where that ends up. Anything that isn't in here just
+// succeeds or fails.
+fn returns(name: &Ident, inputs: &Punctuated<BareFnArg, Comma>, value: &Ident) -> Returns {
+    let arg = |i: usize| inputs[i].name.as_ref().unwrap().0.clone();
+
+    let (ty, out_params, write) = match name.to_string().as_str() {
+        "getattr" | "fgetattr" => {
+            let stat = arg(1);
+            (
+                quote!(crate::FileAttr),
+                vec![1],
+                quote! {
+                    if let Some(stat) = #stat.as_mut() {
+                        *stat = #value.to_stat();
+                    }
+                    0
+                },
+            )
+        }
+        "statfs" => {
+            let statvfs = arg(1);
+            (
+                quote!(crate::StatFs),
+                vec![1],
+                quote! {
+                    if let Some(statvfs) = #statvfs.as_mut() {
+                        *statvfs = #value.to_statvfs();
+                    }
+                    0
+                },
+            )
+        }
+        "readlink" => {
+            let (buf, size) = (arg(1), arg(2));
+            (
+                quote!(std::path::PathBuf),
+                vec![1, 2],
+                quote! {
+                    crate::types::write_link(&#value, #buf, #size as std::primitive::usize);
+                    0
+                },
+            )
+        }
+        "open" | "opendir" | "create" => {
+            let fi = arg(inputs.len() - 1);
+            (
+                quote!(crate::OpenReply<Self::FileHandle>),
+                vec![],
+                quote! {
+                    if let Some(fi) = #fi.as_mut() {
+                        #value.write_to(fi);
+                    }
+                    0
+                },
+            )
+        }
+        "read" | "write" | "write_buf" => (
+            quote!(std::primitive::usize),
+            vec![],
+            quote!(#value.min(std::os::raw::c_int::MAX as std::primitive::usize) as std::os::raw::c_int),
+        ),
+        // These get called twice, once to find out how big the value is and again to get it
+        "getxattr" => {
+            let (buf, size) = (arg(2), arg(3));
+            (
+                quote!(std::vec::Vec<std::primitive::u8>),
+                vec![2, 3],
+                quote!(crate::types::write_xattr(&#value, #buf, #size as std::primitive::usize)),
+            )
+        }
+        // F_GETLK hands back the lock that's in the way through the struct flock it came in
+        "lock" => {
+            let (cmd, lock) = (arg(2), arg(3));
+            (
+                quote!(std::option::Option<crate::Lock>),
+                vec![],
+                quote!(crate::lock::write_conflict(#cmd, #lock, #value)),
+            )
+        }
+        "listxattr" => {
+            let (buf, size) = (arg(1), arg(2));
+            (
+                quote!(std::vec::Vec<std::ffi::OsString>),
+                vec![1, 2],
+                quote!(crate::types::write_xattr_names(&#value, #buf, #size as std::primitive::usize)),
+            )
+        }
+        "bmap" => {
+            let idx = arg(2);
+            (
+                quote!(std::primitive::u64),
+                vec![2],
+                quote! {
+                    if let Some(idx) = #idx.as_mut() {
+                        *idx = #value;
+                    }
+                    0
+                },
+            )
+        }
+        "poll" => {
+            let revents = arg(3);
+            (
+                quote!(std::primitive::u32),
+                vec![3],
+                quote! {
+                    if let Some(revents) = #revents.as_mut() {
+                        *revents = #value as _;
+                    }
+                    0
+                },
+            )
+        }
+        // Whatever ioctl returns goes straight back to the caller
+        "ioctl" => (quote!(std::primitive::i32), vec![], quote!(#value)),
+        _ => (quote!(()), vec![], quote!(0)),
+    };
+
+    Returns {
+        ty,
+        out_params,
+        write,
+    }
+}
+
+// What an operation does with the handle that open left in fuse_file_info::fh
+#[derive(Clone, Copy, PartialEq)]
+enum FileHandleUse {
+    Borrow,
+    // release and releasedir are the last anyone sees of it
+    Take,
+    // The locks still need the lock owner
+    Lock,
+}
+
+fn file_handle_use(name: &Ident) -> Option<FileHandleUse> {
+    match name.to_string().as_str() {
+        "release" | "releasedir" => Some(FileHandleUse::Take),
+        // open, opendir and create are where the handle comes from, so they get the flags instead
+        "open" | "opendir" | "create" => None,
+        "lock" | "flock" => Some(FileHandleUse::Lock),
+        _ => Some(FileHandleUse::Borrow),
+    }
+}
+
 struct UnsafeFnConvert {
     new_inputs: Punctuated<BareFnArg, Comma>,
-    unconverted_call: Punctuated<Expr, Comma>,
     converted_call: Punctuated<Expr, Comma>,
     converted_call_unobfuscated: Punctuated<Expr, Comma>,
     conversion: Punctuated<Stmt, Semi>,
@@ -57,10 +240,10 @@ impl UnsafeFnConvert {
         .unwrap()
     }
 
-    fn new(inputs: Punctuated<BareFnArg, Comma>) -> Self {
+    // `session` is where poll handles find out whether the session they came from is still around
+    fn new(inputs: Punctuated<BareFnArg, Comma>, file_handle: Option<FileHandleUse>, session: TokenStream2) -> Self {
         let mut reexport_types = HashSet::new();
         let mut new_inputs = Punctuated::new();
-        let mut unconverted_call = Punctuated::new();
         let mut converted_call = Punctuated::new();
         let mut converted_call_unobfuscated = Punctuated::new();
         let mut conversions: Vec<Stmt> = vec![];
@@ -69,14 +252,23 @@ impl UnsafeFnConvert {
         let mut inputs = inputs.into_iter();
 
         while let Some(arg) = inputs.next() {
+            let ident = arg.name.unwrap().0;
+
+            // Names are always nul terminated, even when they happen to be followed by a size
+            // (like in the low level getxattr, where the size is for the value).
             let next = lookahead.next();
-            let sized = matches!(&next, Some(next) if is_ident(&next.ty, "size_t") || is_ident(&next.ty, "usize"));
+            // Only buffers count, copy_file_range's fi_out just happens to be followed by its length.
+            let buffer = matches!(&arg.ty, Type::Ptr(TypePtr { elem, .. }) if is_ident(elem, "c_char") || is_ident(elem, "c_void"));
+            let sized = ident != "name"
+                && buffer
+                && matches!(&next, Some(next) if is_ident(&next.ty, "size_t") || is_ident(&next.ty, "usize"));
+            // readdir's buffer is followed by the fuse_fill_dir_t that fills it
+            let fills_dir = matches!(&next, Some(next) if is_ident(&next.ty, "fuse_fill_dir_t"));
+            let forgets = matches!(&next, Some(BareFnArg { ty: Type::Ptr(TypePtr { elem, .. }), .. }) if is_ident(elem, "fuse_forget_data"));
             let size_ident = next.map(|n| n.name.unwrap().0);
 
-            let ident = arg.name.unwrap().0;
             let new_ident = gen_ident(&ident.to_string());
 
-            unconverted_call.push(syn::parse(quote!(#ident).into()).unwrap());
             converted_call.push(syn::parse(quote!(#new_ident).into()).unwrap());
             converted_call_unobfuscated.push(syn::parse(quote!(#ident).into()).unwrap());
 
@@ -92,7 +284,6 @@ impl UnsafeFnConvert {
 
                     inputs.next();
                     let size_ident = size_ident.unwrap();
-                    unconverted_call.push(syn::parse(quote!(#size_ident).into()).unwrap());
 
                     let slice_from: Ident = syn::parse(
                         if mutability.is_none() {
@@ -110,20 +301,197 @@ impl UnsafeFnConvert {
                     ty
                 }
 
+                // The fuse_file_info gets swapped out for whatever open boxed up and stuck in fh.
+                // Until open hands one back there's nothing in there.
+                Type::Ptr(TypePtr { elem, .. })
+                    if file_handle.is_some() && is_ident(&elem, "fuse_file_info") =>
+                {
+                    if file_handle == Some(FileHandleUse::Take) {
+                        conversions.push(syn::parse(quote! {
+                            let #new_ident = #ident
+                                .as_mut()
+                                .filter(|fi| fi.fh != 0)
+                                .map(|fi| *Box::from_raw(std::mem::replace(&mut fi.fh, 0) as *mut _));
+                        }.into()).unwrap());
+                        syn::parse(quote!(Option<Self::FileHandle>).into()).unwrap()
+                    } else {
+                        conversions.push(syn::parse(quote! {
+                            let #new_ident = #ident
+                                .as_ref()
+                                .filter(|fi| fi.fh != 0)
+                                .map(|fi| &*(fi.fh as *const _));
+                        }.into()).unwrap());
+
+                        if file_handle == Some(FileHandleUse::Lock) {
+                            let owner = gen_ident("owner");
+                            conversions.push(
+                                syn::parse(quote!(let #owner = #ident.as_ref().map_or(0, |fi| fi.lock_owner);).into())
+                                    .unwrap(),
+                            );
+                            converted_call.push(syn::parse(quote!(#owner).into()).unwrap());
+                            converted_call_unobfuscated.push(syn::parse(quote!(owner).into()).unwrap());
+
+                            new_inputs.push(syn::parse(quote!(#ident: Option<&Self::FileHandle>).into()).unwrap());
+                            new_inputs.push(syn::parse(quote!(owner: std::primitive::u64).into()).unwrap());
+                            continue;
+                        }
+
+                        syn::parse(quote!(Option<&Self::FileHandle>).into()).unwrap()
+                    }
+                }
+
+                // utimens' timespec[2] holds both the access and modification times
+                Type::Ptr(TypePtr {
+                    mutability: None,
+                    elem,
+                    ..
+                }) if ident == "tv" && is_ident(&elem, "timespec") => {
+                    let atime = gen_ident("atime");
+                    let mtime = gen_ident("mtime");
+
+                    conversions.push(
+                        syn::parse(quote!(let (#atime, #mtime) = crate::types::utimens_times(#ident);).into())
+                            .unwrap(),
+                    );
+                    converted_call.pop();
+                    converted_call.push(syn::parse(quote!(#atime).into()).unwrap());
+                    converted_call.push(syn::parse(quote!(#mtime).into()).unwrap());
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(atime).into()).unwrap());
+                    converted_call_unobfuscated.push(syn::parse(quote!(mtime).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(atime: Option<crate::TimeOrNow>).into()).unwrap());
+                    new_inputs.push(syn::parse(quote!(mtime: Option<crate::TimeOrNow>).into()).unwrap());
+                    continue;
+                }
+
+                // setxattr's flags, which name_args picks out
+                _ if ident == "xattr_flags" => {
+                    conversions.push(
+                        syn::parse(quote!(let #new_ident = crate::XattrFlags::from_raw(#ident);).into()).unwrap(),
+                    );
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(flags).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(flags: crate::XattrFlags).into()).unwrap());
+                    continue;
+                }
+
+                // Whoever gets the poll handle is in charge of destroying it
+                Type::Ptr(TypePtr { elem, .. }) if is_ident(&elem, "fuse_pollhandle") => {
+                    conversions.push(
+                        syn::parse(quote!(let #new_ident = crate::PollHandle::from_raw(#ident, #session);).into()).unwrap(),
+                    );
+                    syn::parse(quote!(Option<crate::PollHandle>).into()).unwrap()
+                }
+
+                // lock's command and the struct flock it applies to, which name_args picks out
+                _ if ident == "lock_cmd" => {
+                    inputs.next();
+                    let lock_ident = size_ident.unwrap();
+
+                    conversions.push(syn::parse(quote! {
+                        let #new_ident = match crate::LockRequest::from_raw(#ident, #lock_ident) {
+                            Some(request) => request,
+                            None => return -crate::Errno::EINVAL.raw(),
+                        };
+                    }.into()).unwrap());
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(request).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(request: crate::LockRequest).into()).unwrap());
+                    continue;
+                }
+
+                // flock's LOCK_SH, LOCK_EX or LOCK_UN, maybe with LOCK_NB
+                _ if ident == "flock_op" => {
+                    let kind = gen_ident("kind");
+                    let wait = gen_ident("wait");
+
+                    conversions.push(syn::parse(quote! {
+                        let (#kind, #wait) = match crate::lock::flock_op(#ident) {
+                            Some(op) => op,
+                            None => return -crate::Errno::EINVAL.raw(),
+                        };
+                    }.into()).unwrap());
+                    converted_call.pop();
+                    converted_call.push(syn::parse(quote!(#kind).into()).unwrap());
+                    converted_call.push(syn::parse(quote!(#wait).into()).unwrap());
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(kind).into()).unwrap());
+                    converted_call_unobfuscated.push(syn::parse(quote!(wait).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(kind: crate::LockKind).into()).unwrap());
+                    new_inputs.push(syn::parse(quote!(wait: std::primitive::bool).into()).unwrap());
+                    continue;
+                }
+
+                // ioctl's command says how big its data is, and which way it's going
+                _ if ident == "ioctl_cmd" => {
+                    conversions.push(
+                        syn::parse(quote!(let #new_ident = crate::IoctlCmd::from_raw(#ident as std::primitive::u32);).into())
+                            .unwrap(),
+                    );
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(cmd).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(cmd: crate::IoctlCmd).into()).unwrap());
+                    continue;
+                }
+
+                _ if ident == "ioctl_data" => {
+                    let cmd_ident = Ident::new("ioctl_cmd", ident.span());
+                    conversions.push(syn::parse(quote! {
+                        let #new_ident = crate::ioctl::buffer(#ident, crate::IoctlCmd::from_raw(#cmd_ident as std::primitive::u32));
+                    }.into()).unwrap());
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(data).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(data: &mut [std::primitive::u8]).into()).unwrap());
+                    continue;
+                }
+
+                // The buffer and fuse_fill_dir_t get rolled into one DirFiller
+                Type::Ptr(_) if fills_dir => {
+                    inputs.next();
+                    let filler_ident = size_ident.unwrap();
+
+                    conversions.push(
+                        syn::parse(quote!(let mut #new_ident = crate::DirFiller::new(#ident, #filler_ident);).into())
+                            .unwrap(),
+                    );
+                    converted_call.pop();
+                    converted_call.push(syn::parse(quote!(&mut #new_ident).into()).unwrap());
+
+                    syn::parse(quote!(&mut crate::DirFiller).into()).unwrap()
+                }
+
                 Type::Ptr(TypePtr {
                     mutability: None,
                     elem,
                     ..
                 }) if is_ident(&elem, "c_char") => {
-                    let ty = syn::parse(quote!(&str).into()).unwrap();
+                    // File names are just bytes on unix, so they can't be &str without
+                    // blowing up on the first one that isn't UTF-8. The low level api
+                    // hands us single names, everything else is a path.
+                    let ty = if ident == "name" || ident == "newname" {
+                        quote!(&std::ffi::OsStr)
+                    } else {
+                        quote!(&std::path::Path)
+                    };
+
                     conversions.push(
                         syn::parse(
-                            quote!(let #new_ident = std::ffi::CStr::from_ptr(#ident).to_str().unwrap();)
-                                .into(),
+                            quote! {
+                                let #new_ident: #ty = <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(
+                                    std::ffi::CStr::from_ptr(#ident).to_bytes()
+                                ).as_ref();
+                            }
+                            .into(),
                         )
                         .unwrap(),
                     );
-                    ty
+                    syn::parse(ty.into()).unwrap()
                 }
 
                 Type::Ptr(TypePtr {
@@ -153,26 +521,24 @@ impl UnsafeFnConvert {
                     ty
                 }
 
-                // fuse_fill_dir is a typedef for an unsafe function pointer.
-                // I'd like to parse it automatically, just like all the other function pointers we deal with
-                // but I can't find a way of extracting the signature of the function pointer from the typedef.
-                //
-                // Here's the signature we are assuming:
-                // pub type fuse_fill_dir_t = Option<unsafe extern "C" fn(buf: *mut c_void, name: *const c_char, stbuf: *const stat, off: off_t) -> c_int>;
-                Type::Path(path) if is_ident(&Type::Path(path.clone()), "fuse_fill_dir_t") => {
+                // forget_multi's count comes before the forgets it's counting
+                Type::Path(_) if forgets => {
+                    inputs.next();
+                    let forgets_ident = size_ident.unwrap();
+                    reexport_types.insert("fuse_forget_data".to_string());
+
                     conversions.push(syn::parse(quote! {
-                        let #new_ident = {
-                            let #ident = #ident.unwrap();
-                            move |buf: Option<&mut std::ffi::c_void>, name: &str, stat: &stat, off: off_t| {
-                                let mut buf = buf.map(|buf| buf as *mut std::ffi::c_void).unwrap_or(0 as *mut std::ffi::c_void);
-                                let name = std::ffi::CString::new(name).unwrap();
-                                let stat = stat as *const stat;
-                                #ident (buf, name.as_ptr(), stat, off)
-                            }
+                        let #new_ident: &[fuse_forget_data] = if #forgets_ident.is_null() {
+                            &[]
+                        } else {
+                            std::slice::from_raw_parts(#forgets_ident, #ident as std::primitive::usize)
                         };
                     }.into()).unwrap());
-                    
-                    syn::parse(quote!(impl Fn(Option<&mut std::ffi::c_void>, &str, &stat, off_t) -> std::os::raw::c_int).into()).unwrap()
+                    converted_call_unobfuscated.pop();
+                    converted_call_unobfuscated.push(syn::parse(quote!(#forgets_ident).into()).unwrap());
+
+                    new_inputs.push(syn::parse(quote!(#forgets_ident: &[fuse_forget_data]).into()).unwrap());
+                    continue;
                 }
 
                 Type::Path(path) => {
@@ -194,7 +560,6 @@ impl UnsafeFnConvert {
 
         Self {
             new_inputs,
-            unconverted_call,
             converted_call,
             converted_call_unobfuscated,
             reexport_types,
@@ -215,7 +580,7 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     let fields = match tokens.fields {
         Fields::Named(fields) => fields.named,
-        _ => unimplemented!(),
+        _ => panic!("fuse_operations should be a struct with named fields"),
     };
 
     let mut raw_unthreaded_fns = TokenStream2::new();
@@ -227,10 +592,17 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut threaded_fns = TokenStream2::new();
 
     let mut blanket_fns = TokenStream2::new();
+    // The same again for smart pointers and references, which hand everything to the T inside
+    let mut shared_fns = TokenStream2::new();
+    let mut owned_fns = TokenStream2::new();
 
     let mut op_assignments: Vec<Stmt> = vec![];
+    let mut all_ops: Vec<String> = vec![];
     let mut all_reexport_types = HashSet::new();
 
+    // How a panicking handler stops the loop
+    let exit = quote!(crate::fuse_exit((*fuse_get_context()).fuse));
+
     for field in fields {
         let name = field.ident.unwrap();
 
@@ -264,6 +636,170 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
             GenericArgument::Type(Type::BareFn(ty)) => ty,
             _ => continue,
         };
+        let inputs = &name_args(&name, inputs.clone());
+
+        // init and destroy don't return a c_int, so they get handled by hand. The threaded
+        // trait gets them with &self like everything else, so that an Arc the application
+        // holds onto too still hands them on.
+        if name == "init" {
+            let conn_ident = match inputs.iter().next() {
+                Some(BareFnArg { name: Some((ident, _)), .. }) => ident.clone(),
+                _ => panic!("init should take a struct fuse_conn_info"),
+            };
+            all_reexport_types.insert("ConnInfo".to_string());
+
+            unthreaded_fns.extend([quote! {
+                fn init(&mut self, conn: &mut ConnInfo) {}
+            }]);
+            threaded_fns.extend([quote! {
+                fn init(&self, conn: &mut ConnInfo) {}
+            }]);
+
+            blanket_fns.extend([quote! {
+                fn init(&mut self, conn: &mut ConnInfo) {
+                    <Self as FileSystem>::init(self, conn)
+                }
+            }]);
+            shared_fns.extend([quote! {
+                fn init(&self, conn: &mut ConnInfo) {
+                    <T as FileSystem>::init(self, conn)
+                }
+            }]);
+            owned_fns.extend([quote! {
+                fn init(&mut self, conn: &mut ConnInfo) {
+                    <T as UnthreadedFileSystem>::init(self, conn)
+                }
+            }]);
+
+            // fuse3 moved most of the high level library's options (use_ino, the cache
+            // timeouts, ...) out of the argument parser and into a struct fuse_config
+            // that gets handed to init.
+            #[cfg(not(feature = "fuse3"))]
+            let config = quote!();
+            #[cfg(feature = "fuse3")]
+            let config = {
+                all_reexport_types.insert("fuse_config".to_string());
+
+                unthreaded_fns.extend([quote! {
+                    fn config(&mut self, config: &mut fuse_config) {}
+                }]);
+                threaded_fns.extend([quote! {
+                    fn config(&self, config: &mut fuse_config) {}
+                }]);
+
+                blanket_fns.extend([quote! {
+                    fn config(&mut self, config: &mut fuse_config) {
+                        <Self as FileSystem>::config(self, config)
+                    }
+                }]);
+                shared_fns.extend([quote! {
+                    fn config(&self, config: &mut fuse_config) {
+                        <T as FileSystem>::config(self, config)
+                    }
+                }]);
+                owned_fns.extend([quote! {
+                    fn config(&mut self, config: &mut fuse_config) {
+                        <T as UnthreadedFileSystem>::config(self, config)
+                    }
+                }]);
+
+                let config_ident = match inputs.iter().last() {
+                    Some(BareFnArg { name: Some((ident, _)), .. }) => ident.clone(),
+                    _ => panic!("fuse3's init should take a struct fuse_config"),
+                };
+
+                quote! {
+                    if let Some(config) = #config_ident.as_mut() {
+                        Self::config(this, config);
+                    }
+                }
+            };
+
+            raw_trait_fn_sigs.extend([quote! {
+                #unsafety #abi fn #name (#inputs) #output;
+            }]);
+
+            let private_data_ident = gen_ident("private");
+
+            for stream in [&mut raw_threaded_fns, &mut raw_unthreaded_fns] {
+                stream.extend([quote! {
+                    #unsafety #abi fn #name (#inputs) #output {
+                        // Whatever init returns replaces the user_data we passed to fuse_main_real,
+                        // so we have to hand it straight back.
+                        let #private_data_ident = (*fuse_get_context()).private_data;
+
+                        crate::unwind::catch(stringify!(#name), || #exit, || {
+                            let this = UserData::<Self>::from_raw(#private_data_ident).this.as_mut().expect("Private data mangled");
+
+                            #config
+
+                            if let Some(conn) = ConnInfo::from_raw(#conn_ident) {
+                                Self::init(this, conn);
+                            }
+                        });
+
+                        #private_data_ident
+                    }
+                }]);
+            }
+
+            op_assignments.push(
+                syn::parse(quote!(operations.#name = Some(Self::#name);).into())
+                    .unwrap(),
+            );
+            continue;
+        }
+
+        if name == "destroy" {
+            let private_data_ident = match inputs.iter().next() {
+                Some(BareFnArg { name: Some((ident, _)), .. }) => ident.clone(),
+                _ => panic!("destroy should take the private data"),
+            };
+
+            unthreaded_fns.extend([quote! {
+                fn destroy(&mut self) {}
+            }]);
+            threaded_fns.extend([quote! {
+                fn destroy(&self) {}
+            }]);
+
+            blanket_fns.extend([quote! {
+                fn destroy(&mut self) {
+                    <Self as FileSystem>::destroy(self)
+                }
+            }]);
+            shared_fns.extend([quote! {
+                fn destroy(&self) {
+                    <T as FileSystem>::destroy(self)
+                }
+            }]);
+            owned_fns.extend([quote! {
+                fn destroy(&mut self) {
+                    <T as UnthreadedFileSystem>::destroy(self)
+                }
+            }]);
+
+            raw_trait_fn_sigs.extend([quote! {
+                #unsafety #abi fn #name (#inputs) #output;
+            }]);
+
+            for stream in [&mut raw_threaded_fns, &mut raw_unthreaded_fns] {
+                stream.extend([quote! {
+                    #unsafety #abi fn #name (#inputs) #output {
+                        crate::unwind::catch(stringify!(#name), || #exit, || {
+                            let this = UserData::<Self>::from_raw(#private_data_ident).this.as_mut().expect("Private data mangled");
+                            Self::destroy(this);
+                        });
+                    }
+                }]);
+            }
+
+            op_assignments.push(
+                syn::parse(quote!(operations.#name = Some(Self::#name);).into())
+                    .unwrap(),
+            );
+            continue;
+        }
 
         if variadic.is_some()
             || !matches!(output, ReturnType::Type(_, ty)
@@ -273,38 +809,60 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
             continue;
         }
 
+        let value_ident = gen_ident("value");
+        let Returns {
+            ty: return_ty,
+            out_params,
+            write,
+        } = returns(&name, inputs, &value_ident);
+
         let UnsafeFnConvert {
             new_inputs,
-            unconverted_call,
             converted_call,
             converted_call_unobfuscated,
             reexport_types,
             conversion,
-        } = UnsafeFnConvert::new(inputs.clone());
+        } = UnsafeFnConvert::new(
+            inputs
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !out_params.contains(i))
+                .map(|(_, arg)| arg.clone())
+                .collect(),
+            file_handle_use(&name),
+            quote!(&UserData::<Self>::from_raw((*fuse_get_context()).private_data).session),
+        );
 
         all_reexport_types.extend(reexport_types);
 
-        let dummy_private_data_ident = gen_ident("dummy_private");
         let private_data_ident = gen_ident("private");
-        let dummy_fs_ident = gen_ident("dummy_fs");
         let out_ident = gen_ident("out");
-
-        let fuse_fs_name: TokenStream2 = format!("crate::fuse_fs_{name}").parse().unwrap();
+        let context_ident = gen_ident("context");
 
         unthreaded_fns.extend([quote! {
-            fn #name (&mut self, #new_inputs) -> std::io::Result<i32> {
-                std::io::Result::Err(std::io::Error::from_raw_os_error(38))
+            fn #name (&mut self, req: &crate::Request, #new_inputs) -> std::result::Result<#return_ty, crate::Errno> {
+                std::result::Result::Err(crate::Errno::ENOSYS)
             }
         }]);
         threaded_fns.extend([quote! {
-            fn #name (&self, #new_inputs) -> std::io::Result<i32> {
-                std::io::Result::Err(std::io::Error::from_raw_os_error(38))
+            fn #name (&self, req: &crate::Request, #new_inputs) -> std::result::Result<#return_ty, crate::Errno> {
+                std::result::Result::Err(crate::Errno::ENOSYS)
             }
         }]);
 
         blanket_fns.extend([quote! {
-            fn #name (&mut self, #new_inputs) -> std::io::Result<i32> {
-                <Self as FileSystem>::#name(self, #converted_call_unobfuscated)
+            fn #name (&mut self, req: &crate::Request, #new_inputs) -> std::result::Result<#return_ty, crate::Errno> {
+                <Self as FileSystem>::#name(self, req, #converted_call_unobfuscated)
+            }
+        }]);
+        shared_fns.extend([quote! {
+            fn #name (&self, req: &crate::Request, #new_inputs) -> std::result::Result<#return_ty, crate::Errno> {
+                <T as FileSystem>::#name(self, req, #converted_call_unobfuscated)
+            }
+        }]);
+        owned_fns.extend([quote! {
+            fn #name (&mut self, req: &crate::Request, #new_inputs) -> std::result::Result<#return_ty, crate::Errno> {
+                <T as UnthreadedFileSystem>::#name(self, req, #converted_call_unobfuscated)
             }
         }]);
     
@@ -315,52 +873,46 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
         for (stream, convert_ptr) in [(&mut raw_threaded_fns, quote!(as_ref)), (&mut raw_unthreaded_fns, quote!(as_mut))] {
             stream.extend([quote! {
                 #unsafety #abi fn #name (#inputs) #output {
-                    #conversion
-
-                    let mut #private_data_ident = UserData::<Self>::from_raw((*fuse_get_context()).private_data);
+                    crate::unwind::catch(stringify!(#name), || #exit, || {
+                        #conversion
 
-                    let #out_ident = Self::#name(
-                        #private_data_ident.this.#convert_ptr().expect("Private data mangled"),
-                        #converted_call
-                    );
-
-                    let #out_ident = match #out_ident {
-                        std::io::Result::Ok(o) => o,
-                        std::io::Result::Err(e) => match e.raw_os_error() {
-                            std::option::Option::Some(os) => -os,
-                            std::option::Option::None => {
-                                eprintln!("Unrecognized error in {}: {:?}", stringify!(#name), e);
-                                -131
-                            }
-                        }
-                    };
+                        let #context_ident = fuse_get_context();
+                        let #private_data_ident = UserData::<Self>::from_raw((*#context_ident).private_data);
 
-                    if #out_ident == -38 {
-                        let mut #dummy_private_data_ident = {
-                            let mut ops = #private_data_ident.ops.clone();
-                            ops.#name = None;
-                            UserData::new(ops, #private_data_ident.this)
-                        };
-                
-                        let #dummy_fs_ident = crate::fuse_fs_new(
-                            &#dummy_private_data_ident.ops as *const _,
-                            std::mem::size_of::<crate::fuse_operations>() /*as crate::size_t*/,
-                            &mut #dummy_private_data_ident as *mut _ as *mut std::ffi::c_void,
+                        let #out_ident = Self::#name(
+                            #private_data_ident.this.#convert_ptr().expect("Private data mangled"),
+                            &crate::Request::from_context(#context_ident),
+                            #converted_call
                         );
 
-                        let out = #fuse_fs_name(#dummy_fs_ident, #unconverted_call);
-
-                        crate::fuse_fs_destroy(#dummy_fs_ident);
-                        out
-                    } else {
-                        #out_ident
-                    }
+                        match #out_ident {
+                            std::result::Result::Ok(#value_ident) => { #write }
+                            std::result::Result::Err(e) => -e.raw(),
+                        }
+                    })
+                    .unwrap_or(-crate::Errno::EIO.raw())
                 }
             }]);
         }
 
+        // The handles open, opendir and create hand out only get dropped by release and
+        // releasedir, so those get registered along with them whether they're implemented or not
+        let name_str = name.to_string();
+        all_ops.push(name_str.clone());
+        let registered_by = match name_str.as_str() {
+            "release" => vec!["release", "open", "create"],
+            "releasedir" => vec!["releasedir", "opendir"],
+            name => vec![name],
+        };
+
+        // Anything that isn't registered is left to libfuse, which fails it with ENOSYS
+        // apart from the few it has an answer for, like opening or statfs
         op_assignments.push(
-            syn::parse(quote!(operations.#name = Some(Self::#name);).into())
+            syn::parse(quote! {
+                if [#(#registered_by),*].iter().any(|op| implemented.contains(op)) {
+                    operations.#name = Some(Self::#name);
+                }
+            }.into())
                 .unwrap(),
         );
     }
@@ -377,49 +929,169 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     #[cfg(feature = "share_threaded_impl")]
     let blanket_impl = quote! {
+        #[allow(clippy::too_many_arguments)]
         impl<F: FileSystem> UnthreadedFileSystem for F {
+            type FileHandle = <F as FileSystem>::FileHandle;
+
+            fn implemented(&self) -> &'static [&'static str] {
+                <F as FileSystem>::implemented(self)
+            }
+
             #blanket_fns
         }
     };
     #[cfg(not(feature = "share_threaded_impl"))]
     let blanket_impl = quote!();
 
+    // These would overlap share_threaded_impl's blanket impl, since a Box of a FileSystem is one
+    // too, and anyone could implement FileSystem for a &mut of their own type
+    #[cfg(feature = "share_threaded_impl")]
+    let owned_impls = quote!();
+    #[cfg(not(feature = "share_threaded_impl"))]
+    let owned_impls = quote! {
+        #[allow(clippy::too_many_arguments)]
+        impl<T: UnthreadedFileSystem + ?Sized> UnthreadedFileSystem for &mut T {
+            type FileHandle = <T as UnthreadedFileSystem>::FileHandle;
+
+            fn implemented(&self) -> &'static [&'static str] {
+                <T as UnthreadedFileSystem>::implemented(self)
+            }
+
+            #owned_fns
+        }
+        #[allow(clippy::too_many_arguments)]
+        impl<T: UnthreadedFileSystem + ?Sized> UnthreadedFileSystem for std::boxed::Box<T> {
+            type FileHandle = <T as UnthreadedFileSystem>::FileHandle;
+
+            fn implemented(&self) -> &'static [&'static str] {
+                <T as UnthreadedFileSystem>::implemented(self)
+            }
+
+            #owned_fns
+        }
+    };
+
+    let pointer_impls = quote! {
+        /// Lets the application hold onto the filesystem while it's mounted.
+        #[allow(clippy::too_many_arguments)]
+        impl<T: FileSystem + ?Sized> FileSystem for std::sync::Arc<T> {
+            type FileHandle = <T as FileSystem>::FileHandle;
+
+            fn implemented(&self) -> &'static [&'static str] {
+                <T as FileSystem>::implemented(self)
+            }
+
+            #shared_fns
+        }
+        #[allow(clippy::too_many_arguments)]
+        impl<T: FileSystem + ?Sized> FileSystem for &T {
+            type FileHandle = <T as FileSystem>::FileHandle;
+
+            fn implemented(&self) -> &'static [&'static str] {
+                <T as FileSystem>::implemented(self)
+            }
+
+            #shared_fns
+        }
+        #[allow(clippy::too_many_arguments)]
+        impl<T: FileSystem + ?Sized> FileSystem for std::boxed::Box<T> {
+            type FileHandle = <T as FileSystem>::FileHandle;
+
+            fn implemented(&self) -> &'static [&'static str] {
+                <T as FileSystem>::implemented(self)
+            }
+
+            #shared_fns
+        }
+
+        #owned_impls
+    };
+
+    // Impls that don't say what they implement register everything, like they did before
+    // there was a choice, and get ENOSYS from whatever they left out
+    let implemented_doc = quote! {
+        /// The names of the operations that get registered with libfuse. Anything left out
+        /// is up to libfuse, which fails most of them with `ENOSYS` but has its own answer
+        /// for a few, like opening files. Put `#[fuse_sys::filesystem]` on the impl block to
+        /// have this filled in with the methods in it.
+        ///
+        /// Without that every operation gets registered, and the ones that aren't implemented
+        /// fail with `ENOSYS`, `open` and `opendir` included. That's how impls written before
+        /// this existed keep working, and adding `#[filesystem]` to them is all it takes to
+        /// get libfuse's defaults instead.
+    };
+
     quote! {
-        #[allow(unused_variables)]
-        pub trait UnthreadedFileSystem: Sized {
+        #[allow(unused_variables, clippy::too_many_arguments)]
+        pub trait UnthreadedFileSystem {
+            /// Whatever `open`, `opendir` and `create` want to keep around for an open file.
+            type FileHandle;
+            #implemented_doc
+            fn implemented(&self) -> &'static [&'static str] {
+                &[#(#all_ops),*]
+            }
+
             #unthreaded_fns
         }
-        pub trait FileSystem: Sized {
+        /// A filesystem that gets requests from several threads at once.
+        ///
+        /// It's object safe, so a filesystem picked at runtime can be mounted as a
+        /// `Box<dyn FileSystem<FileHandle = H> + Send + Sync>`, as long as every
+        /// filesystem it could be agrees on `H`.
+        #[allow(unused_variables, clippy::too_many_arguments)]
+        pub trait FileSystem {
+            /// Whatever `open`, `opendir` and `create` want to keep around for an open file.
+            type FileHandle: Send + Sync;
+            #implemented_doc
+            fn implemented(&self) -> &'static [&'static str] {
+                &[#(#all_ops),*]
+            }
+
             #threaded_fns
         }
 
         #blanket_impl
+        #pointer_impls
 
         pub trait FileSystemRaw<const UNTHREADED: bool> {
+            fn implemented(&self) -> &'static [&'static str];
+
             #raw_trait_fn_sigs
         }
         impl<F: UnthreadedFileSystem> FileSystemRaw<true> for F {
+            fn implemented(&self) -> &'static [&'static str] {
+                <F as UnthreadedFileSystem>::implemented(self)
+            }
+
             #raw_unthreaded_fns
         }
         impl<F: FileSystem + Send + Sync> FileSystemRaw<false> for F {
+            fn implemented(&self) -> &'static [&'static str] {
+                <F as FileSystem>::implemented(self)
+            }
+
             #raw_threaded_fns
         }
 
         pub trait FuseMain<const UNTHREADED: bool>: FileSystemRaw<UNTHREADED> + 'static {
-            fn run(self, fuse_args: &[&str]) -> Result<(), i32>;
+            /// Mounts and serves the filesystem until it's unmounted, then hands it back.
+            fn run(self, options: &crate::MountOptions) -> Result<Self, crate::MountError>
+            where
+                Self: Sized;
+
+            /// The operations libfuse gets, which are only the ones the filesystem implements.
+            #[doc(hidden)]
+            fn operations(&self) -> crate::fuse_operations;
         }
 
         struct UserData<T> {
-            ops: crate::fuse_operations,
             this: *mut T,
+            session: crate::poll::Liveness,
         }
 
         impl<T> UserData<T> {
-            fn new(ops: crate::fuse_operations, this: *mut T) -> Self {
-                Self {
-                    ops,
-                    this,
-                }
+            fn new(this: *mut T, session: crate::poll::Liveness) -> Self {
+                Self { this, session }
             }
 
             unsafe fn from_raw<'a>(raw: *mut std::ffi::c_void) -> &'a Self {
@@ -428,38 +1100,16 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
         }
 
         impl<const UNTHREADED: bool, F: FileSystemRaw<UNTHREADED> + 'static> FuseMain<UNTHREADED> for F {
-            fn run(self, fuse_args: &[&str]) -> Result<(), i32> {
-                let mut operations = crate::fuse_operations::default();
-                #op_assignments
-
-                let mut this = self;
-                let mut user_data = UserData::new(
-                    operations.clone(),
-                    &mut this as *mut _,
-                );
-
-                let mut args_owned: std::vec::Vec<_> = fuse_args.into_iter().map(|s| std::ffi::CString::new(*s).unwrap()).collect();
-            
-                if UNTHREADED {
-                    args_owned.push(std::ffi::CString::new("-s").unwrap());
-                }
-
-                let mut args: std::vec::Vec<_> = args_owned.iter_mut().map(|cs| cs.as_ptr()).collect();
+            fn run(self, options: &crate::MountOptions) -> Result<Self, crate::MountError> {
+                crate::session::run::<UNTHREADED, Self>(self, options)
+            }
 
-                let out = unsafe {
-                    crate::fuse_main_real(
-                        args.len() as i32,
-                        args.as_mut_ptr() as *mut *mut std::os::raw::c_char,
-                        &operations as *const crate::fuse_operations,
-                        std::mem::size_of::<crate::fuse_operations>() /*as crate::size_t*/,
-                        &mut user_data as *mut _ as *mut std::ffi::c_void,
-                    )
-                };
+            fn operations(&self) -> crate::fuse_operations {
+                let implemented = <Self as FileSystemRaw<UNTHREADED>>::implemented(self);
 
-                match out {
-                    0 => Ok(()),
-                    e => Err(e),
-                }
+                let mut operations = crate::fuse_operations::default();
+                #op_assignments
+                operations
             }
         }
 
@@ -468,6 +1118,30 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
                 UnthreadedFileSystem,
                 FileSystem,
                 FuseMain,
+                MountOptions,
+                MountError,
+                ThreadingConfig,
+                spawn_mount,
+                BackgroundSession,
+                ShutdownHandle,
+                filesystem,
+                Errno,
+                ToErrno,
+                Request,
+                FileAttr,
+                StatFs,
+                OpenReply,
+                TimeOrNow,
+                XattrFlags,
+                Lock,
+                LockKind,
+                LockRequest,
+                LockManager,
+                PollHandle,
+                IoctlCmd,
+                IoctlPayload,
+                IoctlRegistry,
+                DirFiller,
                 #reexport_list
             };
         }
@@ -475,3 +1149,35 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
         #out
     }.into()
 }
+
+/// Goes on an `impl FileSystem` or `impl UnthreadedFileSystem` block and fills in
+/// `implemented` with the names of the methods in it.
+#[proc_macro_attribute]
+pub fn filesystem(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item = parse_macro_input!(item as ItemImpl);
+
+    let implemented: Vec<String> = item
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            ImplItem::Method(method) => Some(method.sig.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    item.items.push(
+        syn::parse(quote! {
+            fn implemented(&self) -> &'static [&'static str] {
+                &[#(#implemented),*]
+            }
+        }.into())
+            .unwrap(),
+    );
+
+    quote!(#item).into()
+}
+
+#[proc_macro_attribute]
+pub fn fuse_lowlevel_ops(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    lowlevel::fuse_lowlevel_ops(item)
+}
@@ -0,0 +1,590 @@
+use std::collections::HashSet;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input,
+    punctuated::Punctuated,
+    token::{Comma, Semi},
+    BareFnArg, Fields, GenericArgument, Ident, ItemStruct, PathArguments, Stmt, Type, TypeBareFn,
+};
+
+use crate::{gen_ident, UnsafeFnConvert, PRIMITIVE_IDENTS};
+
+// Unlike the high level api, the low level api answers requests by calling fuse_reply_*
+// instead of returning something, so we need to know which reply goes with which operation.
+// Anything that isn't listed here isn't a request, and apart from init and destroy gets skipped.
+const REPLIES: &[(&str, &str)] = &[
+    ("lookup", "ReplyEntry"),
+    ("forget", "ReplyNone"),
+    ("getattr", "ReplyAttr"),
+    ("setattr", "ReplyAttr"),
+    ("readlink", "ReplyReadlink"),
+    ("mknod", "ReplyEntry"),
+    ("mkdir", "ReplyEntry"),
+    ("unlink", "ReplyEmpty"),
+    ("rmdir", "ReplyEmpty"),
+    ("symlink", "ReplyEntry"),
+    ("rename", "ReplyEmpty"),
+    ("link", "ReplyEntry"),
+    ("open", "ReplyOpen"),
+    ("read", "ReplyData"),
+    ("write", "ReplyWrite"),
+    ("flush", "ReplyEmpty"),
+    ("release", "ReplyEmpty"),
+    ("fsync", "ReplyEmpty"),
+    ("opendir", "ReplyOpen"),
+    ("readdir", "ReplyDirectory"),
+    ("releasedir", "ReplyEmpty"),
+    ("fsyncdir", "ReplyEmpty"),
+    ("statfs", "ReplyStatfs"),
+    ("setxattr", "ReplyEmpty"),
+    ("getxattr", "ReplyXattr"),
+    ("listxattr", "ReplyXattr"),
+    ("removexattr", "ReplyEmpty"),
+    ("access", "ReplyEmpty"),
+    ("create", "ReplyCreate"),
+    ("getlk", "ReplyLock"),
+    ("setlk", "ReplyEmpty"),
+    ("bmap", "ReplyBmap"),
+    ("ioctl", "ReplyIoctl"),
+    ("poll", "ReplyPoll"),
+    ("write_buf", "ReplyWrite"),
+    ("retrieve_reply", "ReplyNone"),
+    ("forget_multi", "ReplyNone"),
+    ("flock", "ReplyEmpty"),
+    ("fallocate", "ReplyEmpty"),
+];
+
+#[cfg(feature = "fuse3")]
+const FUSE3_REPLIES: &[(&str, &str)] = &[
+    ("readdirplus", "ReplyDirectoryPlus"),
+    ("copy_file_range", "ReplyWrite"),
+    ("lseek", "ReplyLseek"),
+];
+#[cfg(not(feature = "fuse3"))]
+const FUSE3_REPLIES: &[(&str, &str)] = &[];
+
+// Directory replies are backed by a buffer as big as the kernel asked for.
+const SIZED_REPLIES: &[&str] = &["ReplyDirectory", "ReplyDirectoryPlus"];
+
+// These hand over pointers that are only good until the callback returns and can't be copied,
+// so the async filesystem leaves them out. libfuse falls back to write and forget without
+// write_buf and forget_multi.
+#[cfg(feature = "tokio")]
+const NOT_ASYNC: &[&str] = &["ioctl", "write_buf", "retrieve_reply", "forget_multi"];
+
+pub fn fuse_lowlevel_ops(item: TokenStream) -> TokenStream {
+    let out: TokenStream2 = item.clone().into();
+    let tokens = parse_macro_input!(item as ItemStruct);
+
+    let fields = match tokens.fields {
+        Fields::Named(fields) => fields.named,
+        _ => panic!("fuse_lowlevel_ops should be a struct with named fields"),
+    };
+
+    let mut raw_fns = TokenStream2::new();
+    let mut raw_trait_fn_sigs = TokenStream2::new();
+    let mut trait_fns = TokenStream2::new();
+
+    let mut op_assignments: Vec<Stmt> = vec![];
+    #[cfg(feature = "tokio")]
+    let (mut async_raw_fns, mut async_raw_trait_fn_sigs, mut async_trait_fns, mut async_op_assignments) =
+        (TokenStream2::new(), TokenStream2::new(), TokenStream2::new(), Vec::<Stmt>::new());
+    let mut all_reexport_types = HashSet::new();
+
+    for field in fields {
+        let name = field.ident.unwrap();
+
+        let reply = REPLIES
+            .iter()
+            .chain(FUSE3_REPLIES)
+            .find(|(op, _)| name == op)
+            .map(|(_, reply)| *reply);
+        if reply.is_none() && name != "init" && name != "destroy" {
+            continue;
+        }
+
+        let ty_path = match field.ty {
+            Type::Path(path) => path,
+            _ => continue,
+        };
+
+        let ty = ty_path.path.segments.last().unwrap();
+        if ty.ident != "Option" {
+            continue;
+        }
+
+        let args = match &ty.arguments {
+            PathArguments::AngleBracketed(args) => args,
+            _ => continue,
+        };
+
+        let TypeBareFn {
+            unsafety,
+            abi,
+            inputs,
+            output,
+            ..
+        } = match args.args.first().unwrap() {
+            GenericArgument::Type(Type::BareFn(ty)) => ty,
+            _ => continue,
+        };
+
+        // init and destroy get the user data instead of a request, and don't answer anything
+        let reply = match reply {
+            Some(reply) => reply,
+            None => {
+                let (trait_fn, call) = if name == "init" {
+                    all_reexport_types.insert("ConnInfo".to_string());
+
+                    let conn_ident = match inputs.iter().nth(1) {
+                        Some(BareFnArg { name: Some((ident, _)), .. }) => ident.clone(),
+                        _ => panic!("init should take a struct fuse_conn_info"),
+                    };
+                    (
+                        quote!(fn init(&mut self, conn: &mut crate::ConnInfo) {}),
+                        quote! {
+                            if let Some(conn) = crate::ConnInfo::from_raw(#conn_ident) {
+                                Self::init(this, conn);
+                            }
+                        },
+                    )
+                } else {
+                    (quote!(fn destroy(&mut self) {}), quote!(Self::destroy(this);))
+                };
+
+                let user_data_ident = match inputs.iter().next() {
+                    Some(BareFnArg { name: Some((ident, _)), .. }) => ident.clone(),
+                    _ => panic!("{} should take the user data", name),
+                };
+
+                trait_fns.extend([trait_fn]);
+
+                raw_trait_fn_sigs.extend([quote! {
+                    #unsafety #abi fn #name (#inputs) #output;
+                }]);
+
+                raw_fns.extend([quote! {
+                    #unsafety #abi fn #name (#inputs) #output {
+                        crate::unwind::catch(stringify!(#name), exit_session, || {
+                            let this = (#user_data_ident as *mut Self).as_mut().expect("Private data mangled");
+                            #call
+                        });
+                    }
+                }]);
+
+                op_assignments
+                    .push(syn::parse(quote!(operations.#name = Some(Self::#name);).into()).unwrap());
+                continue;
+            }
+        };
+        let reply_ident: Ident = syn::parse(reply.parse().unwrap()).unwrap();
+
+        // Every request handler starts with the fuse_req_t that the reply has to be sent to.
+        let mut request_inputs = inputs.clone().into_iter();
+        let req_ident = request_inputs.next().unwrap().name.unwrap().0;
+
+        let UnsafeFnConvert {
+            new_inputs,
+            converted_call,
+            reexport_types,
+            conversion,
+            ..
+        } = UnsafeFnConvert::new(
+            request_inputs.collect(),
+            None,
+            quote!(&crate::poll::Liveness::current(&POLL_SESSION)),
+        );
+
+        all_reexport_types.extend(reexport_types);
+
+        let this_ident = gen_ident("this");
+        let reply_var_ident = gen_ident("reply");
+        let request_ident = gen_ident("request");
+
+        let new_reply = if SIZED_REPLIES.contains(&reply) {
+            let size_ident = inputs
+                .iter()
+                .filter_map(|arg| arg.name.as_ref().map(|n| &n.0))
+                .find(|ident| *ident == "size")
+                .expect("Sized replies need a size argument");
+            quote!(crate::reply::#reply_ident::new(#req_ident, #size_ident as std::primitive::usize))
+        } else {
+            quote!(crate::reply::#reply_ident::new(#req_ident))
+        };
+
+        trait_fns.extend([quote! {
+            fn #name (&mut self, req: &crate::Request, #new_inputs, reply: crate::reply::#reply_ident) {
+                crate::reply::Reply::error(reply, crate::Errno::ENOSYS)
+            }
+        }]);
+
+        raw_trait_fn_sigs.extend([quote! {
+            #unsafety #abi fn #name (#inputs) #output;
+        }]);
+
+        raw_fns.extend([quote! {
+            #unsafety #abi fn #name (#inputs) #output {
+                // The reply gets made first, so that if anything after it panics
+                // it's dropped on the way out and answers with EIO.
+                crate::unwind::catch(stringify!(#name), exit_session, || {
+                    let #reply_var_ident = #new_reply;
+                    let #request_ident = crate::Request::from_req(#req_ident);
+
+                    #conversion
+
+                    let #this_ident = (crate::fuse_req_userdata(#req_ident) as *mut Self)
+                        .as_mut()
+                        .expect("Private data mangled");
+
+                    Self::#name(#this_ident, &#request_ident, #converted_call, #reply_var_ident)
+                });
+            }
+        }]);
+
+        op_assignments
+            .push(syn::parse(quote!(operations.#name = Some(Self::#name);).into()).unwrap());
+
+        #[cfg(feature = "tokio")]
+        if !NOT_ASYNC.contains(&name.to_string().as_str()) {
+            let tasks_ident = gen_ident("tasks");
+
+            // Everything the handler gets is copied out before the callback returns,
+            // and lent back to it from inside the task.
+            let owned_idents: Vec<Ident> = converted_call.iter().map(|_| gen_ident("owned")).collect();
+            let detached = converted_call.iter().zip(&owned_idents).map(|(call, owned)| {
+                quote!(let mut #owned = crate::tasks::Detach::detach(#call);)
+            });
+            let attached = owned_idents
+                .iter()
+                .map(|owned| quote!(crate::tasks::Detach::attach(&mut #owned)));
+
+            async_trait_fns.extend([quote! {
+                fn #name (&self, req: &crate::Request, #new_inputs, reply: crate::reply::#reply_ident)
+                    -> impl std::future::Future<Output = ()> + Send
+                {
+                    async move { crate::reply::Reply::error(reply, crate::Errno::ENOSYS) }
+                }
+            }]);
+
+            async_raw_trait_fn_sigs.extend([quote! {
+                #unsafety #abi fn #name (#inputs) #output;
+            }]);
+
+            async_raw_fns.extend([quote! {
+                #unsafety #abi fn #name (#inputs) #output {
+                    crate::unwind::catch(stringify!(#name), exit_session, || {
+                        let #reply_var_ident = #new_reply;
+                        let #request_ident = crate::Request::from_req(#req_ident);
+
+                        #conversion
+
+                        let #tasks_ident = (crate::fuse_req_userdata(#req_ident) as *const crate::tasks::Tasks<Self>)
+                            .as_ref()
+                            .expect("Private data mangled");
+                        let #this_ident = #tasks_ident.fs.clone();
+
+                        #(#detached)*
+
+                        #tasks_ident.spawn(stringify!(#name), exit_session, async move {
+                            <Self as AsyncFileSystem>::#name(&#this_ident, &#request_ident, #(#attached,)* #reply_var_ident).await
+                        });
+                    });
+                }
+            }]);
+
+            async_op_assignments.push(
+                syn::parse(quote!(operations.#name = Some(<Self as AsyncFileSystemRaw>::#name);).into())
+                    .unwrap(),
+            );
+        }
+    }
+
+    let op_assignments: Punctuated<Stmt, Semi> = op_assignments.into_iter().collect();
+
+    let reexport_list: Punctuated<Type, Comma> = all_reexport_types
+        .into_iter()
+        .filter_map(|s| {
+            (!PRIMITIVE_IDENTS.contains(&s.as_ref()))
+                .then(|| syn::parse::<Type>(s.parse().unwrap()).unwrap())
+        })
+        .collect();
+
+    let run = run_body(
+        &op_assignments,
+        quote!(&mut this as *mut Self as *mut std::ffi::c_void),
+        quote!(),
+        quote!(this),
+    );
+
+    #[cfg(feature = "tokio")]
+    let async_fs = {
+        let async_op_assignments: Punctuated<Stmt, Semi> = async_op_assignments.into_iter().collect();
+        let run = run_body(
+            &async_op_assignments,
+            quote!(&tasks as *const crate::tasks::Tasks<Self> as *mut std::ffi::c_void),
+            quote!(tasks.wait();),
+            // Every future's done by now, and each one's copy of the Arc with it
+            quote!(std::sync::Arc::into_inner(tasks.fs).expect("A request outlived the session")),
+        );
+
+        quote! {
+            /// The low level api again, except handlers are futures that get spawned onto a tokio
+            /// runtime, so any number of requests can be worked on at once without a thread each.
+            /// Every method gets who's making the request in `req`, and has to answer it through
+            /// `reply`, which can happen after any number of awaits.
+            ///
+            /// `ioctl` isn't available, since what it gets can't outlive the call.
+            #[allow(unused_variables, clippy::too_many_arguments)]
+            pub trait AsyncFileSystem: Send + Sync + Sized + 'static {
+                #async_trait_fns
+            }
+
+            pub trait AsyncFileSystemRaw {
+                #async_raw_trait_fn_sigs
+            }
+            impl<F: AsyncFileSystem> AsyncFileSystemRaw for F {
+                #async_raw_fns
+            }
+
+            pub trait AsyncMain: AsyncFileSystemRaw + 'static {
+                /// Mounts and serves the filesystem until it's unmounted, running every request
+                /// on `runtime`. Once the filesystem is unmounted, this waits for the requests
+                /// that are still running before it returns.
+                ///
+                /// This blocks, so it shouldn't be called from one of `runtime`'s own threads
+                /// (`spawn_blocking` is fine).
+                fn run(self, runtime: &tokio::runtime::Handle, options: &crate::MountOptions) -> Result<Self, crate::MountError>
+                where
+                    Self: Sized;
+            }
+
+            impl<F: AsyncFileSystemRaw + 'static> AsyncMain for F {
+                fn run(self, runtime: &tokio::runtime::Handle, options: &crate::MountOptions) -> Result<Self, crate::MountError> {
+                    let tasks = crate::tasks::Tasks::new(self, runtime.clone());
+                    #run
+                }
+            }
+        }
+    };
+    #[cfg(not(feature = "tokio"))]
+    let async_fs = quote!();
+
+    #[cfg(feature = "tokio")]
+    let async_reexports = quote!(AsyncFileSystem, AsyncMain,);
+    #[cfg(not(feature = "tokio"))]
+    let async_reexports = quote!();
+
+    quote! {
+        /// A filesystem built on libfuse's low level api, which addresses files by inode
+        /// instead of by path. Every request handler gets who's making it in `req`, and has
+        /// to answer it through `reply`.
+        #[allow(unused_variables, clippy::too_many_arguments)]
+        pub trait LowLevelFileSystem: Sized {
+            #trait_fns
+        }
+
+        // The running session, so that a panicking handler can stop it
+        static SESSION: std::sync::atomic::AtomicPtr<crate::fuse_session> =
+            std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+
+        // Whether the running session's still around, for the poll handles it hands out
+        static POLL_SESSION: std::sync::Mutex<Option<crate::poll::Liveness>> = std::sync::Mutex::new(None);
+
+        fn exit_session() {
+            let session = SESSION.load(std::sync::atomic::Ordering::SeqCst);
+            if !session.is_null() {
+                unsafe { crate::fuse_session_exit(session) };
+            }
+        }
+
+        pub trait LowLevelFileSystemRaw {
+            #raw_trait_fn_sigs
+        }
+        impl<F: LowLevelFileSystem> LowLevelFileSystemRaw for F {
+            #raw_fns
+        }
+
+        pub trait LowLevelMain: LowLevelFileSystemRaw + 'static {
+            /// Mounts and serves the filesystem until it's unmounted, then hands it back.
+            /// Requests are always handled one at a time, since handlers take `&mut self`.
+            fn run(self, options: &crate::MountOptions) -> Result<Self, crate::MountError>
+            where
+                Self: Sized;
+        }
+
+        impl<F: LowLevelFileSystemRaw + 'static> LowLevelMain for F {
+            fn run(self, options: &crate::MountOptions) -> Result<Self, crate::MountError> {
+                let mut this = self;
+                #run
+            }
+        }
+
+        #async_fs
+
+        pub mod lowlevel {
+            pub use crate::{
+                LowLevelFileSystem,
+                LowLevelMain,
+                #async_reexports
+                MountOptions,
+                MountError,
+                Errno,
+                reply::*,
+                #reexport_list
+            };
+        }
+
+        #out
+    }
+    .into()
+}
+
+// The body of a run function, which sets up the fuse_args and operations and runs the session.
+// `this` gets the filesystem back out once the session's over.
+fn run_body(
+    op_assignments: &Punctuated<Stmt, Semi>,
+    user_data: TokenStream2,
+    drain: TokenStream2,
+    this: TokenStream2,
+) -> TokenStream2 {
+    let session_loop = session_loop(user_data, drain);
+
+    quote! {
+        let mut operations = crate::fuse_lowlevel_ops::default();
+        #op_assignments
+
+        let mut args_owned = options.to_args(false);
+        let mut argv: std::vec::Vec<_> = args_owned.iter_mut().map(|cs| cs.as_ptr() as *mut std::os::raw::c_char).collect();
+
+        let mut args = crate::fuse_args {
+            argc: argv.len() as i32,
+            argv: argv.as_mut_ptr(),
+            allocated: 0,
+        };
+
+        let out = unsafe {
+            #session_loop
+
+            crate::fuse_opt_free_args(&mut args);
+            out
+        };
+
+        out.map(|()| #this)
+    }
+}
+
+// Mounts the filesystem and serves it until it's unmounted. Requests get `user_data`,
+// and `drain` runs as soon as the loop stops, while replies can still be sent.
+#[cfg(not(feature = "fuse3"))]
+fn session_loop(user_data: TokenStream2, drain: TokenStream2) -> TokenStream2 {
+    quote! {
+        let mut mountpoint = std::ptr::null_mut();
+        let mut foreground = 0;
+        if crate::fuse_parse_cmdline(&mut args, &mut mountpoint, std::ptr::null_mut(), &mut foreground) == -1
+            || mountpoint.is_null()
+        {
+            crate::fuse_opt_free_args(&mut args);
+            libc::free(mountpoint as *mut std::ffi::c_void);
+            return Err(crate::MountError::Mount(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "libfuse couldn't parse the options",
+            )));
+        }
+
+        let chan = crate::fuse_mount(mountpoint, &mut args);
+        let mut out = Err(crate::MountError::Mount(std::io::Error::other(
+            "libfuse couldn't mount the filesystem",
+        )));
+
+        if !chan.is_null() {
+            let session = crate::fuse_lowlevel_new(
+                &mut args,
+                &operations as *const crate::fuse_lowlevel_ops,
+                std::mem::size_of::<crate::fuse_lowlevel_ops>(),
+                #user_data,
+            );
+
+            if !session.is_null() {
+                SESSION.store(session, std::sync::atomic::Ordering::SeqCst);
+                *POLL_SESSION.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = Some(crate::poll::Liveness::new());
+
+                if crate::fuse_set_signal_handlers(session) != -1 {
+                    crate::fuse_session_add_chan(session, chan);
+                    crate::fuse_daemonize(foreground);
+
+                    out = match crate::fuse_session_loop(session) {
+                        0 => Ok(()),
+                        e => Err(crate::MountError::Session(e)),
+                    };
+                    #drain
+
+                    crate::fuse_remove_signal_handlers(session);
+                    crate::fuse_session_remove_chan(chan);
+                }
+                SESSION.store(std::ptr::null_mut(), std::sync::atomic::Ordering::SeqCst);
+                if let Some(poll_session) = POLL_SESSION.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take() {
+                    poll_session.end();
+                }
+                crate::fuse_session_destroy(session);
+            }
+            crate::fuse_unmount(mountpoint, chan);
+        }
+        // fuse_parse_cmdline hands over a copy of the mountpoint
+        libc::free(mountpoint as *mut std::ffi::c_void);
+    }
+}
+
+#[cfg(feature = "fuse3")]
+fn session_loop(user_data: TokenStream2, drain: TokenStream2) -> TokenStream2 {
+    quote! {
+        let mut opts = crate::fuse_cmdline_opts::default();
+        if crate::fuse_parse_cmdline(&mut args, &mut opts) != 0 || opts.mountpoint.is_null() {
+            crate::fuse_opt_free_args(&mut args);
+            libc::free(opts.mountpoint as *mut std::ffi::c_void);
+            return Err(crate::MountError::Mount(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "libfuse couldn't parse the options",
+            )));
+        }
+
+        let session = crate::fuse_session_new(
+            &mut args,
+            &operations as *const crate::fuse_lowlevel_ops,
+            std::mem::size_of::<crate::fuse_lowlevel_ops>(),
+            #user_data,
+        );
+        let mut out = Err(crate::MountError::Mount(std::io::Error::other(
+            "libfuse couldn't mount the filesystem",
+        )));
+
+        if !session.is_null() {
+            SESSION.store(session, std::sync::atomic::Ordering::SeqCst);
+            *POLL_SESSION.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = Some(crate::poll::Liveness::new());
+
+            if crate::fuse_set_signal_handlers(session) == 0 {
+                if crate::fuse_session_mount(session, opts.mountpoint) == 0 {
+                    crate::fuse_daemonize(opts.foreground);
+
+                    out = match crate::fuse_session_loop(session) {
+                        0 => Ok(()),
+                        e => Err(crate::MountError::Session(e)),
+                    };
+                    #drain
+
+                    crate::fuse_session_unmount(session);
+                }
+                crate::fuse_remove_signal_handlers(session);
+            }
+            SESSION.store(std::ptr::null_mut(), std::sync::atomic::Ordering::SeqCst);
+            if let Some(poll_session) = POLL_SESSION.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take() {
+                poll_session.end();
+            }
+            crate::fuse_session_destroy(session);
+        }
+        // fuse_parse_cmdline hands over a copy of the mountpoint
+        libc::free(opts.mountpoint as *mut std::ffi::c_void);
+    }
+}
@@ -0,0 +1,432 @@
+// Stands in for the bindgen output of wrapper.h when building with the pure_rust feature.
+//
+// Everything here mirrors what bindgen generates from libfuse 2.9's <fuse.h> on a 64 bit
+// linux with _FILE_OFFSET_BITS=64, so that the fuse_operations macro generates the exact
+// same traits it does when building against libfuse. The difference is that
+// fuse_main_real and fuse_get_context come from src/kernel instead of the C library.
+
+pub use crate::kernel::{fuse_exit, fuse_get_context, fuse_main_real};
+
+pub type mode_t = ::std::os::raw::c_uint;
+pub type dev_t = ::std::os::raw::c_ulong;
+pub type uid_t = ::std::os::raw::c_uint;
+pub type gid_t = ::std::os::raw::c_uint;
+pub type pid_t = ::std::os::raw::c_int;
+pub type off_t = ::std::os::raw::c_long;
+pub type time_t = ::std::os::raw::c_long;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct timespec {
+    pub tv_sec: time_t,
+    pub tv_nsec: ::std::os::raw::c_long,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct stat {
+    pub st_dev: dev_t,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: mode_t,
+    pub st_uid: uid_t,
+    pub st_gid: gid_t,
+    pub __pad0: ::std::os::raw::c_int,
+    pub st_rdev: dev_t,
+    pub st_size: off_t,
+    pub st_blksize: ::std::os::raw::c_long,
+    pub st_blocks: i64,
+    pub st_atim: timespec,
+    pub st_mtim: timespec,
+    pub st_ctim: timespec,
+    pub __glibc_reserved: [::std::os::raw::c_long; 3usize],
+}
+
+// aarch64 and riscv64 both use the kernel's generic stat layout
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct stat {
+    pub st_dev: dev_t,
+    pub st_ino: u64,
+    pub st_mode: mode_t,
+    pub st_nlink: ::std::os::raw::c_uint,
+    pub st_uid: uid_t,
+    pub st_gid: gid_t,
+    pub st_rdev: dev_t,
+    pub __pad1: dev_t,
+    pub st_size: off_t,
+    pub st_blksize: ::std::os::raw::c_int,
+    pub __pad2: ::std::os::raw::c_int,
+    pub st_blocks: i64,
+    pub st_atim: timespec,
+    pub st_mtim: timespec,
+    pub st_ctim: timespec,
+    pub __glibc_reserved: [::std::os::raw::c_int; 2usize],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct statvfs {
+    pub f_bsize: ::std::os::raw::c_ulong,
+    pub f_frsize: ::std::os::raw::c_ulong,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_favail: u64,
+    pub f_fsid: ::std::os::raw::c_ulong,
+    pub f_flag: ::std::os::raw::c_ulong,
+    pub f_namemax: ::std::os::raw::c_ulong,
+    pub __f_spare: [::std::os::raw::c_int; 6usize],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct flock {
+    pub l_type: ::std::os::raw::c_short,
+    pub l_whence: ::std::os::raw::c_short,
+    pub l_start: off_t,
+    pub l_len: off_t,
+    pub l_pid: pid_t,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct fuse_file_info {
+    pub flags: ::std::os::raw::c_int,
+    pub fh_old: ::std::os::raw::c_ulong,
+    pub writepage: ::std::os::raw::c_int,
+    pub _bitfield_1: ::std::os::raw::c_uint,
+    pub fh: u64,
+    pub lock_owner: u64,
+}
+
+// bindgen turns the one bit fields of fuse_file_info into getters and setters
+macro_rules! bitfield_accessors {
+    ($($get:ident, $set:ident, $bit:expr;)*) => {
+        impl fuse_file_info {
+            $(
+                #[inline]
+                pub fn $get(&self) -> ::std::os::raw::c_uint {
+                    (self._bitfield_1 >> $bit) & 1
+                }
+
+                #[inline]
+                pub fn $set(&mut self, val: ::std::os::raw::c_uint) {
+                    self._bitfield_1 = (self._bitfield_1 & !(1 << $bit)) | ((val & 1) << $bit);
+                }
+            )*
+        }
+    };
+}
+
+bitfield_accessors! {
+    direct_io, set_direct_io, 0;
+    keep_cache, set_keep_cache, 1;
+    flush, set_flush, 2;
+    nonseekable, set_nonseekable, 3;
+    flock_release, set_flock_release, 4;
+}
+
+pub const FUSE_CAP_ASYNC_READ: u32 = 1;
+pub const FUSE_CAP_POSIX_LOCKS: u32 = 2;
+pub const FUSE_CAP_ATOMIC_O_TRUNC: u32 = 8;
+pub const FUSE_CAP_EXPORT_SUPPORT: u32 = 16;
+pub const FUSE_CAP_BIG_WRITES: u32 = 32;
+pub const FUSE_CAP_DONT_MASK: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct fuse_conn_info {
+    pub proto_major: ::std::os::raw::c_uint,
+    pub proto_minor: ::std::os::raw::c_uint,
+    pub async_read: ::std::os::raw::c_uint,
+    pub max_write: ::std::os::raw::c_uint,
+    pub max_readahead: ::std::os::raw::c_uint,
+    pub capable: ::std::os::raw::c_uint,
+    pub want: ::std::os::raw::c_uint,
+    pub max_background: ::std::os::raw::c_uint,
+    pub congestion_threshold: ::std::os::raw::c_uint,
+    pub reserved: [::std::os::raw::c_uint; 23usize],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct fuse_context {
+    pub fuse: *mut fuse,
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub pid: pid_t,
+    pub private_data: *mut ::std::os::raw::c_void,
+    pub umask: mode_t,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct fuse {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct fuse_pollhandle {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct fuse_bufvec {
+    _unused: [u8; 0],
+}
+
+pub type fuse_fill_dir_t = ::std::option::Option<
+    unsafe extern "C" fn(
+        buf: *mut ::std::os::raw::c_void,
+        name: *const ::std::os::raw::c_char,
+        stbuf: *const stat,
+        off: off_t,
+    ) -> ::std::os::raw::c_int,
+>;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct fuse_operations {
+    pub getattr: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut stat) -> ::std::os::raw::c_int,
+    >,
+    pub readlink: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut ::std::os::raw::c_char,
+            arg3: usize,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub mknod: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: mode_t, arg3: dev_t) -> ::std::os::raw::c_int,
+    >,
+    pub mkdir: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: mode_t) -> ::std::os::raw::c_int,
+    >,
+    pub unlink: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int,
+    >,
+    pub rmdir: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char) -> ::std::os::raw::c_int,
+    >,
+    pub symlink: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub rename: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub link: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub chmod: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: mode_t) -> ::std::os::raw::c_int,
+    >,
+    pub chown: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: uid_t, arg3: gid_t) -> ::std::os::raw::c_int,
+    >,
+    pub truncate: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: off_t) -> ::std::os::raw::c_int,
+    >,
+    pub open: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut fuse_file_info) -> ::std::os::raw::c_int,
+    >,
+    pub read: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut ::std::os::raw::c_char,
+            arg3: usize,
+            arg4: off_t,
+            arg5: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub write: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+            arg3: usize,
+            arg4: off_t,
+            arg5: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub statfs: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut statvfs) -> ::std::os::raw::c_int,
+    >,
+    pub flush: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut fuse_file_info) -> ::std::os::raw::c_int,
+    >,
+    pub release: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut fuse_file_info) -> ::std::os::raw::c_int,
+    >,
+    pub fsync: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: ::std::os::raw::c_int,
+            arg3: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub setxattr: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+            arg3: *const ::std::os::raw::c_char,
+            arg4: usize,
+            arg5: ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub getxattr: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+            arg3: *mut ::std::os::raw::c_char,
+            arg4: usize,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub listxattr: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut ::std::os::raw::c_char,
+            arg3: usize,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub removexattr: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *const ::std::os::raw::c_char,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub opendir: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut fuse_file_info) -> ::std::os::raw::c_int,
+    >,
+    pub readdir: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut ::std::os::raw::c_void,
+            arg3: fuse_fill_dir_t,
+            arg4: off_t,
+            arg5: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub releasedir: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: *mut fuse_file_info) -> ::std::os::raw::c_int,
+    >,
+    pub fsyncdir: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: ::std::os::raw::c_int,
+            arg3: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub init: ::std::option::Option<
+        unsafe extern "C" fn(conn: *mut fuse_conn_info) -> *mut ::std::os::raw::c_void,
+    >,
+    pub destroy: ::std::option::Option<unsafe extern "C" fn(arg1: *mut ::std::os::raw::c_void)>,
+    pub access: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, arg2: ::std::os::raw::c_int) -> ::std::os::raw::c_int,
+    >,
+    pub create: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: mode_t,
+            arg3: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub ftruncate: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: off_t,
+            arg3: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub fgetattr: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut stat,
+            arg3: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub lock: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut fuse_file_info,
+            cmd: ::std::os::raw::c_int,
+            arg3: *mut flock,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub utimens: ::std::option::Option<
+        unsafe extern "C" fn(arg1: *const ::std::os::raw::c_char, tv: *const timespec) -> ::std::os::raw::c_int,
+    >,
+    pub bmap: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            blocksize: usize,
+            idx: *mut u64,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub ioctl: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            cmd: ::std::os::raw::c_int,
+            arg: *mut ::std::os::raw::c_void,
+            arg2: *mut fuse_file_info,
+            flags: ::std::os::raw::c_uint,
+            data: *mut ::std::os::raw::c_void,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub poll: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut fuse_file_info,
+            ph: *mut fuse_pollhandle,
+            reventsp: *mut ::std::os::raw::c_uint,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub write_buf: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            buf: *mut fuse_bufvec,
+            off: off_t,
+            arg2: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub read_buf: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            bufp: *mut *mut fuse_bufvec,
+            size: usize,
+            off: off_t,
+            arg2: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub flock: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut fuse_file_info,
+            op: ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int,
+    >,
+    pub fallocate: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: ::std::os::raw::c_int,
+            arg3: off_t,
+            arg4: off_t,
+            arg5: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+}
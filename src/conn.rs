@@ -0,0 +1,106 @@
+use crate::fuse_conn_info;
+
+/// The connection parameters handed to `init`.
+///
+/// `capable` is everything the kernel (and libfuse) can do,
+/// `want` is the subset of that we're asking for. Anything
+/// that isn't wrapped here can still be reached through [`ConnInfo::raw_mut`].
+#[repr(transparent)]
+pub struct ConnInfo(fuse_conn_info);
+
+impl ConnInfo {
+    pub(crate) unsafe fn from_raw<'a>(raw: *mut fuse_conn_info) -> Option<&'a mut Self> {
+        (raw as *mut Self).as_mut()
+    }
+
+    /// The major and minor version of the kernel protocol.
+    pub fn proto_version(&self) -> (u32, u32) {
+        (self.0.proto_major, self.0.proto_minor)
+    }
+
+    /// Whether the `FUSE_CAP_*` flag `cap` is supported.
+    pub fn capable(&self, cap: u32) -> bool {
+        self.0.capable & cap == cap
+    }
+
+    /// Whether the `FUSE_CAP_*` flag `cap` is currently asked for.
+    pub fn wants(&self, cap: u32) -> bool {
+        self.0.want & cap == cap
+    }
+
+    /// Asks for (or stops asking for) the `FUSE_CAP_*` flag `cap`.
+    /// Returns false and does nothing if it isn't supported.
+    pub fn set_want(&mut self, cap: u32, want: bool) -> bool {
+        if !self.capable(cap) {
+            return false;
+        }
+
+        if want {
+            self.0.want |= cap;
+        } else {
+            self.0.want &= !cap;
+        }
+        true
+    }
+
+    // fuse3 folded the async_read field into the capability flags
+    #[cfg(not(feature = "fuse3"))]
+    pub fn async_read(&self) -> bool {
+        self.0.async_read != 0
+    }
+
+    #[cfg(not(feature = "fuse3"))]
+    pub fn set_async_read(&mut self, async_read: bool) {
+        self.0.async_read = async_read as _;
+    }
+
+    #[cfg(feature = "fuse3")]
+    pub fn async_read(&self) -> bool {
+        self.wants(crate::FUSE_CAP_ASYNC_READ)
+    }
+
+    #[cfg(feature = "fuse3")]
+    pub fn set_async_read(&mut self, async_read: bool) {
+        self.set_want(crate::FUSE_CAP_ASYNC_READ, async_read);
+    }
+
+    pub fn max_write(&self) -> u32 {
+        self.0.max_write
+    }
+
+    pub fn set_max_write(&mut self, max_write: u32) {
+        self.0.max_write = max_write;
+    }
+
+    pub fn max_readahead(&self) -> u32 {
+        self.0.max_readahead
+    }
+
+    pub fn set_max_readahead(&mut self, max_readahead: u32) {
+        self.0.max_readahead = max_readahead;
+    }
+
+    pub fn max_background(&self) -> u32 {
+        self.0.max_background
+    }
+
+    pub fn set_max_background(&mut self, max_background: u32) {
+        self.0.max_background = max_background;
+    }
+
+    pub fn congestion_threshold(&self) -> u32 {
+        self.0.congestion_threshold
+    }
+
+    pub fn set_congestion_threshold(&mut self, congestion_threshold: u32) {
+        self.0.congestion_threshold = congestion_threshold;
+    }
+
+    pub fn raw(&self) -> &fuse_conn_info {
+        &self.0
+    }
+
+    pub fn raw_mut(&mut self) -> &mut fuse_conn_info {
+        &mut self.0
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    ffi::{c_void, OsStr},
+    os::{raw::c_char, unix::ffi::OsStrExt},
+};
+
+use crate::{fuse_fill_dir_t, off_t, FileAttr};
+
+/// Collects the entries `readdir` lists.
+///
+/// There are two ways of listing a directory:
+///
+/// - Pass 0 as every entry's `next_offset` and add everything in one go.
+///   libfuse keeps the whole listing around and hands it to the kernel a
+///   chunk at a time, so the `offset` given to readdir can be ignored.
+/// - Pass each entry's offset, which is whatever `offset` readdir should be called
+///   with to carry on listing right after it, and stop once [`DirFiller::add`]
+///   says the buffer is full. The next call starts from `offset`, so huge
+///   directories never have to be read in one go.
+///
+/// Mixing the two in one listing doesn't work.
+pub struct DirFiller {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    // Reused between entries so names don't each need their own allocation
+    name: Vec<u8>,
+}
+
+impl DirFiller {
+    pub(crate) unsafe fn new(buf: *mut c_void, filler: fuse_fill_dir_t) -> Self {
+        Self {
+            buf,
+            filler,
+            name: Vec::new(),
+        }
+    }
+
+    /// Adds an entry to the listing. Only the inode and the file type
+    /// in `attr.mode` are looked at, unless libfuse was asked to use the inode
+    /// numbers it's given.
+    ///
+    /// Returns true if the buffer is full, in which case the entry wasn't added.
+    /// Names with a nul in them can't be file names, so they're skipped.
+    pub fn add(&mut self, name: &OsStr, attr: &FileAttr, next_offset: off_t) -> bool {
+        self.fill(name, attr, next_offset, 0)
+    }
+
+    /// Same as [`DirFiller::add`], except `attr` is complete and
+    /// libfuse gets to skip looking the entry up when the kernel asked for readdirplus.
+    #[cfg(feature = "fuse3")]
+    pub fn add_plus(&mut self, name: &OsStr, attr: &FileAttr, next_offset: off_t) -> bool {
+        self.fill(
+            name,
+            attr,
+            next_offset,
+            crate::fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS,
+        )
+    }
+
+    #[cfg_attr(not(feature = "fuse3"), allow(unused_variables))]
+    fn fill(&mut self, name: &OsStr, attr: &FileAttr, next_offset: off_t, flags: u32) -> bool {
+        let filler = match self.filler {
+            Some(filler) => filler,
+            None => return true,
+        };
+        if self.buf.is_null() || name.as_bytes().contains(&0) {
+            return false;
+        }
+
+        self.name.clear();
+        self.name.extend_from_slice(name.as_bytes());
+        self.name.push(0);
+
+        let stat = attr.to_stat();
+        let name = self.name.as_ptr() as *const c_char;
+
+        #[cfg(not(feature = "fuse3"))]
+        let full = unsafe { filler(self.buf, name, &stat, next_offset) };
+        #[cfg(feature = "fuse3")]
+        let full = unsafe { filler(self.buf, name, &stat, next_offset, flags as _) };
+
+        full != 0
+    }
+}
@@ -0,0 +1,293 @@
+//! Errors as fuse sees them.
+//!
+//! Handlers fail with an [`Errno`], and anything implementing [`ToErrno`] turns into one
+//! with `?`. That covers [`std::io::Error`] out of the box. For your own error types
+//! implement [`ToErrno`] to pick the errno each variant should show up as.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    os::raw::c_int,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+/// An error number, like `ENOENT`, that gets handed back to the kernel.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Errno(c_int);
+
+macro_rules! errnos {
+    ($($name:ident),* $(,)?) => {
+        impl Errno {
+            $(pub const $name: Self = Self(libc::$name);)*
+        }
+
+        fn name(errno: Errno) -> Option<&'static str> {
+            match errno.0 {
+                $(libc::$name => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+errnos![
+    EPERM,
+    ENOENT,
+    ESRCH,
+    EINTR,
+    EIO,
+    ENXIO,
+    E2BIG,
+    EBADF,
+    EAGAIN,
+    ENOMEM,
+    EACCES,
+    EFAULT,
+    EBUSY,
+    EEXIST,
+    EXDEV,
+    ENODEV,
+    ENOTDIR,
+    EISDIR,
+    EINVAL,
+    ENFILE,
+    EMFILE,
+    ENOTTY,
+    ETXTBSY,
+    EFBIG,
+    ENOSPC,
+    ESPIPE,
+    EROFS,
+    EMLINK,
+    EPIPE,
+    ERANGE,
+    EDEADLK,
+    ENAMETOOLONG,
+    ENOLCK,
+    ENOSYS,
+    ENOTEMPTY,
+    ELOOP,
+    ENODATA,
+    ENOTSUP,
+    ETIMEDOUT,
+    ECONNREFUSED,
+    ECONNRESET,
+    ECONNABORTED,
+    ENOTCONN,
+    EADDRINUSE,
+    EADDRNOTAVAIL,
+    EDQUOT,
+    ESTALE,
+    EILSEQ,
+    ENOTRECOVERABLE,
+];
+
+impl Errno {
+    /// Anything that isn't a positive errno turns into `EIO`, since handing the kernel
+    /// 0 or less would tell it the request succeeded.
+    pub const fn from_raw(errno: c_int) -> Self {
+        if errno > 0 {
+            Self(errno)
+        } else {
+            Self::EIO
+        }
+    }
+
+    pub const fn raw(self) -> c_int {
+        self.0
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match name(*self) {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "Errno({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", io::Error::from_raw_os_error(self.0))
+    }
+}
+
+impl std::error::Error for Errno {}
+
+impl From<Errno> for io::Error {
+    fn from(errno: Errno) -> Self {
+        io::Error::from_raw_os_error(errno.0)
+    }
+}
+
+static FALLBACK: AtomicI32 = AtomicI32::new(libc::EIO);
+
+/// Sets what errors that don't map to anything more specific turn into. `EIO` by default.
+pub fn set_fallback_errno(errno: Errno) {
+    FALLBACK.store(errno.0, Ordering::Relaxed);
+}
+
+pub fn fallback_errno() -> Errno {
+    Errno(FALLBACK.load(Ordering::Relaxed))
+}
+
+/// Errors that can be handed back to fuse.
+pub trait ToErrno {
+    fn to_errno(&self) -> Errno;
+}
+
+// Errno deliberately doesn't implement ToErrno, since that would
+// keep this from coexisting with the blanket `From<T> for T`.
+impl<E: ToErrno> From<E> for Errno {
+    fn from(e: E) -> Self {
+        e.to_errno()
+    }
+}
+
+impl ToErrno for ErrorKind {
+    fn to_errno(&self) -> Errno {
+        let errno = match self {
+            ErrorKind::NotFound => libc::ENOENT,
+            ErrorKind::PermissionDenied => libc::EACCES,
+            ErrorKind::ConnectionRefused => libc::ECONNREFUSED,
+            ErrorKind::ConnectionReset => libc::ECONNRESET,
+            ErrorKind::ConnectionAborted => libc::ECONNABORTED,
+            ErrorKind::NotConnected => libc::ENOTCONN,
+            ErrorKind::AddrInUse => libc::EADDRINUSE,
+            ErrorKind::AddrNotAvailable => libc::EADDRNOTAVAIL,
+            ErrorKind::BrokenPipe => libc::EPIPE,
+            ErrorKind::AlreadyExists => libc::EEXIST,
+            ErrorKind::WouldBlock => libc::EAGAIN,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => libc::EINVAL,
+            ErrorKind::TimedOut => libc::ETIMEDOUT,
+            ErrorKind::Interrupted => libc::EINTR,
+            ErrorKind::Unsupported => libc::ENOSYS,
+            ErrorKind::OutOfMemory => libc::ENOMEM,
+            _ => return fallback_errno(),
+        };
+
+        Errno(errno)
+    }
+}
+
+impl ToErrno for io::Error {
+    fn to_errno(&self) -> Errno {
+        match self.raw_os_error() {
+            Some(errno) if errno > 0 => Errno(errno),
+            _ => match self.get_ref().and_then(|e| e.downcast_ref::<Errno>()) {
+                Some(errno) => *errno,
+                None => self.kind().to_errno(),
+            },
+        }
+    }
+}
+
+/// Goes looking through the error's sources for something that knows its errno.
+impl ToErrno for Box<dyn std::error::Error + Send + Sync> {
+    fn to_errno(&self) -> Errno {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&**self);
+        while let Some(e) = source {
+            if let Some(errno) = e.downcast_ref::<Errno>() {
+                return *errno;
+            }
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                return e.to_errno();
+            }
+            source = e.source();
+        }
+
+        fallback_errno()
+    }
+}
+
+#[cfg(feature = "anyhow")]
+impl ToErrno for anyhow::Error {
+    fn to_errno(&self) -> Errno {
+        for e in self.chain() {
+            if let Some(errno) = e.downcast_ref::<Errno>() {
+                return *errno;
+            }
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                return e.to_errno();
+            }
+        }
+
+        fallback_errno()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_kinds() {
+        assert_eq!(ErrorKind::NotFound.to_errno(), Errno::ENOENT);
+        assert_eq!(ErrorKind::PermissionDenied.to_errno(), Errno::EACCES);
+        assert_eq!(ErrorKind::AlreadyExists.to_errno(), Errno::EEXIST);
+        assert_eq!(ErrorKind::WouldBlock.to_errno(), Errno::EAGAIN);
+        assert_eq!(ErrorKind::InvalidData.to_errno(), Errno::EINVAL);
+        assert_eq!(ErrorKind::Unsupported.to_errno(), Errno::ENOSYS);
+    }
+
+    #[test]
+    fn io_errors() {
+        assert_eq!(io::Error::from_raw_os_error(libc::ENOTEMPTY).to_errno(), Errno::ENOTEMPTY);
+        assert_eq!(io::Error::from(Errno::EROFS).to_errno(), Errno::EROFS);
+        assert_eq!(io::Error::other(Errno::EXDEV).to_errno(), Errno::EXDEV);
+        assert_eq!(io::Error::new(ErrorKind::NotFound, "gone").to_errno(), Errno::ENOENT);
+    }
+
+    #[test]
+    fn boxed_sources() {
+        #[derive(Debug)]
+        struct Wrapper(io::Error);
+
+        impl fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "wrapped")
+            }
+        }
+
+        impl std::error::Error for Wrapper {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let e: Box<dyn std::error::Error + Send + Sync> = Box::new(Wrapper(io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert_eq!(e.to_errno(), Errno::ENOSPC);
+        assert_eq!(Errno::from(e), Errno::ENOSPC);
+    }
+
+    // Everything that reads the fallback is in here, since it's shared by every test
+    #[test]
+    fn fallback() {
+        let unknown: Box<dyn std::error::Error + Send + Sync> = "no errno in here".into();
+
+        assert_eq!(fallback_errno(), Errno::EIO);
+        assert_eq!(ErrorKind::Other.to_errno(), Errno::EIO);
+        assert_eq!(unknown.to_errno(), Errno::EIO);
+
+        set_fallback_errno(Errno::EPERM);
+        assert_eq!(ErrorKind::Other.to_errno(), Errno::EPERM);
+        assert_eq!(io::Error::other("nope").to_errno(), Errno::EPERM);
+        assert_eq!(unknown.to_errno(), Errno::EPERM);
+        set_fallback_errno(Errno::EIO);
+    }
+
+    #[test]
+    fn debug_names() {
+        assert_eq!(format!("{:?}", Errno::ENOENT), "ENOENT");
+        assert_eq!(format!("{:?}", Errno::from_raw(4000)), "Errno(4000)");
+    }
+
+    #[test]
+    fn not_errnos() {
+        assert_eq!(Errno::from_raw(libc::ENOENT), Errno::ENOENT);
+        assert_eq!(Errno::from_raw(0), Errno::EIO);
+        assert_eq!(Errno::from_raw(-libc::ENOENT), Errno::EIO);
+        assert_eq!(Errno::from_raw(c_int::MIN).raw(), libc::EIO);
+    }
+}
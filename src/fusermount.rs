@@ -0,0 +1,143 @@
+//! Mounting and unmounting through the setuid `fusermount` helper,
+//! the same way libfuse does it for unprivileged users.
+//!
+//! libfuse can only unmount a filesystem from the thread that's running it,
+//! so [`unmount`] gets used on top of libfuse too, to stop a loop from the outside.
+
+#![cfg_attr(not(feature = "pure_rust"), allow(dead_code))]
+
+use std::{
+    ffi::OsStr,
+    io,
+    process::{Command, Stdio},
+};
+#[cfg(feature = "pure_rust")]
+use std::{mem, os::unix::io::RawFd};
+
+// fuse3 only ships fusermount3, fuse2 only ships fusermount,
+// and they both speak the same protocol.
+const FUSERMOUNT: &[&str] = &["fusermount3", "fusermount"];
+
+fn fusermount() -> Command {
+    let found = FUSERMOUNT.iter().find(|bin| {
+        Command::new(bin)
+            .arg("-V")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok()
+    });
+
+    Command::new(found.unwrap_or(&FUSERMOUNT[0]))
+}
+
+/// Mounts a fuse filesystem at `mountpoint` and returns the /dev/fuse file descriptor for it.
+#[cfg(feature = "pure_rust")]
+pub fn mount(mountpoint: &OsStr, options: &[String]) -> io::Result<RawFd> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [ours, theirs] = fds;
+
+    // fusermount finds its end of the socket through the environment.
+    // socketpair doesn't set CLOEXEC, so the child inherits it.
+    let mut command = fusermount();
+    if !options.is_empty() {
+        command.arg("-o").arg(options.join(","));
+    }
+    command
+        .arg("--")
+        .arg(mountpoint)
+        .env("_FUSE_COMMFD", theirs.to_string());
+
+    let status = command.status();
+    unsafe { libc::close(theirs) };
+
+    let fd = match status {
+        Ok(status) if status.success() => receive_fd(ours),
+        Ok(status) => Err(io::Error::other(format!("fusermount failed with {status}"))),
+        Err(e) => Err(e),
+    };
+    unsafe { libc::close(ours) };
+
+    let fd = fd?;
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(fd)
+}
+
+/// Lazily unmounts `mountpoint`, which works even if the filesystem is still busy.
+#[cfg(not(target_os = "macos"))]
+pub fn unmount(mountpoint: &OsStr) -> io::Result<()> {
+    let status = fusermount()
+        .arg("-u")
+        .arg("-q")
+        .arg("-z")
+        .arg("--")
+        .arg(mountpoint)
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "fusermount -u {} failed with {status}",
+            mountpoint.to_string_lossy()
+        )))
+    }
+}
+
+/// macOS has no fusermount, but plain umount works for the user that mounted it.
+#[cfg(target_os = "macos")]
+pub fn unmount(mountpoint: &OsStr) -> io::Result<()> {
+    let status = Command::new("umount")
+        .arg(mountpoint)
+        .stderr(Stdio::null())
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "umount {} failed with {status}",
+            mountpoint.to_string_lossy()
+        )))
+    }
+}
+
+// fusermount opens /dev/fuse and passes it back over the socket with SCM_RIGHTS
+#[cfg(feature = "pure_rust")]
+fn receive_fd(socket: RawFd) -> io::Result<RawFd> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    loop {
+        if unsafe { libc::recvmsg(socket, &mut msg, 0) } != -1 {
+            break;
+        }
+
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::other("fusermount didn't send a file descriptor"));
+    }
+
+    Ok(unsafe { (libc::CMSG_DATA(cmsg) as *const RawFd).read_unaligned() })
+}
@@ -0,0 +1,382 @@
+//! ioctl commands, and handing them out to typed handlers.
+//!
+//! The high level library only passes along ioctls that say how big their argument is
+//! and which way it goes, using the same encoding as `_IOR` and friends. The kernel
+//! copies that many bytes in and out, and that's the buffer `ioctl` gets.
+
+use std::{collections::HashMap, fmt, mem, os::raw::c_void, ptr, slice};
+
+use crate::Errno;
+
+// powerpc, mips and sparc use the same layout as the BSDs, with a bit more room for the direction
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+))]
+mod encoding {
+    pub const SIZE_BITS: u32 = 13;
+    pub const NONE: u32 = 1;
+    pub const READ: u32 = 2;
+    pub const WRITE: u32 = 4;
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+)))]
+mod encoding {
+    pub const SIZE_BITS: u32 = 14;
+    pub const NONE: u32 = 0;
+    pub const WRITE: u32 = 1;
+    pub const READ: u32 = 2;
+}
+
+use encoding::{NONE, READ, SIZE_BITS, WRITE};
+
+const SIZE_SHIFT: u32 = 16;
+const DIR_SHIFT: u32 = SIZE_SHIFT + SIZE_BITS;
+
+/// An ioctl command number, put together like the C macros do it. Directions are from
+/// the point of view of whoever calls `ioctl`, so a `read` command hands a `T` back to them.
+///
+/// ```
+/// # use fuse_sys::IoctlCmd;
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// struct Stats {
+///     hits: u64,
+///     misses: u64,
+/// }
+///
+/// const GET_STATS: IoctlCmd = IoctlCmd::read::<Stats>(b'S', 1);
+/// assert_eq!(GET_STATS.size(), 16);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IoctlCmd(u32);
+
+impl IoctlCmd {
+    const fn new(dir: u32, ty: u8, nr: u8, size: usize) -> Self {
+        assert!(size < 1 << SIZE_BITS, "ioctl arguments can't be that big");
+        Self(dir << DIR_SHIFT | (size as u32) << SIZE_SHIFT | (ty as u32) << 8 | nr as u32)
+    }
+
+    /// `_IO`, nothing goes either way.
+    pub const fn none(ty: u8, nr: u8) -> Self {
+        Self::new(NONE, ty, nr, 0)
+    }
+
+    /// `_IOR`, the caller gets a `T` back.
+    pub const fn read<T>(ty: u8, nr: u8) -> Self {
+        Self::new(READ, ty, nr, mem::size_of::<T>())
+    }
+
+    /// `_IOW`, the caller hands over a `T`.
+    pub const fn write<T>(ty: u8, nr: u8) -> Self {
+        Self::new(WRITE, ty, nr, mem::size_of::<T>())
+    }
+
+    /// `_IOWR`, the caller hands over a `T` and gets one back in its place.
+    pub const fn read_write<T>(ty: u8, nr: u8) -> Self {
+        Self::new(READ | WRITE, ty, nr, mem::size_of::<T>())
+    }
+
+    pub const fn from_raw(cmd: u32) -> Self {
+        Self(cmd)
+    }
+
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+
+    pub const fn ty(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub const fn nr(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// How big the argument is, going by the command.
+    pub const fn size(self) -> usize {
+        ((self.0 >> SIZE_SHIFT) & ((1 << SIZE_BITS) - 1)) as usize
+    }
+
+    /// Whether the caller gets something back.
+    pub const fn reads(self) -> bool {
+        (self.0 >> DIR_SHIFT) & READ != 0
+    }
+
+    /// Whether the caller hands something over.
+    pub const fn writes(self) -> bool {
+        (self.0 >> DIR_SHIFT) & WRITE != 0
+    }
+
+    // Commands that don't go either way don't get a buffer, whatever their size says
+    fn buffer_len(self) -> usize {
+        if self.reads() || self.writes() {
+            self.size()
+        } else {
+            0
+        }
+    }
+}
+
+// The buffer libfuse copies the argument in and out of, as big as the command says it is
+pub(crate) unsafe fn buffer<'a>(data: *mut c_void, cmd: IoctlCmd) -> &'a mut [u8] {
+    let len = cmd.buffer_len();
+    if data.is_null() || len == 0 {
+        return &mut [];
+    }
+    slice::from_raw_parts_mut(data as *mut u8, len)
+}
+
+/// Something that can go through an ioctl as is, byte for byte.
+///
+/// # Safety
+///
+/// It has to be `#[repr(C)]` (or a primitive) with no pointers or references in it, and
+/// any bytes at all have to make a valid one, since that's what the caller can send.
+pub unsafe trait IoctlPayload: Copy + 'static {}
+
+macro_rules! payloads {
+    ($($ty:ty),*) => {
+        $(unsafe impl IoctlPayload for $ty {})*
+    };
+}
+
+payloads!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, usize, isize);
+
+unsafe impl<T: IoctlPayload, const N: usize> IoctlPayload for [T; N] {}
+
+type Handler<F> = Box<dyn Fn(&F, &mut [u8]) -> Result<(), Errno> + Send + Sync>;
+
+/// Ties ioctl commands to typed handlers, for a filesystem to [`dispatch`](Self::dispatch)
+/// its `ioctl` calls to.
+///
+/// ```no_run
+/// # use fuse_sys::prelude::*;
+/// # use std::{path::Path, sync::atomic::{AtomicU64, Ordering}};
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// struct Stats {
+///     hits: u64,
+///     misses: u64,
+/// }
+///
+/// unsafe impl IoctlPayload for Stats {}
+///
+/// const GET_STATS: IoctlCmd = IoctlCmd::read::<Stats>(b'S', 1);
+/// const SET_HITS: IoctlCmd = IoctlCmd::write::<u64>(b'S', 2);
+///
+/// struct Cache {
+///     hits: AtomicU64,
+///     ioctls: IoctlRegistry<Cache>,
+/// }
+///
+/// impl Cache {
+///     fn new() -> Self {
+///         let ioctls = IoctlRegistry::new()
+///             .read(GET_STATS, |cache: &Cache| {
+///                 Ok(Stats { hits: cache.hits.load(Ordering::Relaxed), misses: 0 })
+///             })
+///             .write(SET_HITS, |cache: &Cache, hits: u64| {
+///                 cache.hits.store(hits, Ordering::Relaxed);
+///                 Ok(())
+///             });
+///
+///         Self { hits: AtomicU64::new(0), ioctls }
+///     }
+/// }
+///
+/// #[filesystem]
+/// impl FileSystem for Cache {
+///     type FileHandle = ();
+///
+///     fn ioctl(&self, _req: &Request, _path: &Path, cmd: IoctlCmd, _arg: Option<&mut std::ffi::c_void>, _fh: Option<&()>, _flags: u32, data: &mut [u8]) -> Result<i32, Errno> {
+///         self.ioctls.dispatch(self, cmd, data)
+///     }
+/// }
+/// ```
+pub struct IoctlRegistry<F: ?Sized> {
+    handlers: HashMap<u32, Handler<F>>,
+}
+
+impl<F: ?Sized> IoctlRegistry<F> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Panics if `cmd` isn't the direction it's being registered as, or isn't the size of a `T`.
+    fn register<T>(mut self, cmd: IoctlCmd, reads: bool, writes: bool, handler: Handler<F>) -> Self {
+        assert!(
+            cmd.reads() == reads && cmd.writes() == writes && cmd.size() == mem::size_of::<T>(),
+            "{cmd:?} doesn't match the handler it's being registered with"
+        );
+        self.handlers.insert(cmd.raw(), handler);
+        self
+    }
+
+    /// Handles an `_IO` command.
+    pub fn none(self, cmd: IoctlCmd, handler: impl Fn(&F) -> Result<(), Errno> + Send + Sync + 'static) -> Self {
+        self.register::<()>(cmd, false, false, Box::new(move |fs, _| handler(fs)))
+    }
+
+    /// Handles an `_IOR` command, whose handler comes up with the `T` that goes back.
+    pub fn read<T: IoctlPayload>(
+        self,
+        cmd: IoctlCmd,
+        handler: impl Fn(&F) -> Result<T, Errno> + Send + Sync + 'static,
+    ) -> Self {
+        self.register::<T>(
+            cmd,
+            true,
+            false,
+            Box::new(move |fs, data| {
+                let out = handler(fs)?;
+                unsafe { ptr::write_unaligned(data.as_mut_ptr() as *mut T, out) };
+                Ok(())
+            }),
+        )
+    }
+
+    /// Handles an `_IOW` command, whose handler gets the `T` that came in.
+    pub fn write<T: IoctlPayload>(
+        self,
+        cmd: IoctlCmd,
+        handler: impl Fn(&F, T) -> Result<(), Errno> + Send + Sync + 'static,
+    ) -> Self {
+        self.register::<T>(
+            cmd,
+            false,
+            true,
+            Box::new(move |fs, data| handler(fs, unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })),
+        )
+    }
+
+    /// Handles an `_IOWR` command, whose handler gets the `T` that came in and hands one back.
+    pub fn read_write<T: IoctlPayload>(
+        self,
+        cmd: IoctlCmd,
+        handler: impl Fn(&F, T) -> Result<T, Errno> + Send + Sync + 'static,
+    ) -> Self {
+        self.register::<T>(
+            cmd,
+            true,
+            true,
+            Box::new(move |fs, data| {
+                let out = handler(fs, unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })?;
+                unsafe { ptr::write_unaligned(data.as_mut_ptr() as *mut T, out) };
+                Ok(())
+            }),
+        )
+    }
+
+    /// Runs whatever's registered for `cmd`, with what `ioctl` got for `data`. Commands
+    /// nobody registered fail with `ENOTTY`, like they would on any other file.
+    pub fn dispatch(&self, fs: &F, cmd: IoctlCmd, data: &mut [u8]) -> Result<i32, Errno> {
+        let handler = self.handlers.get(&cmd.raw()).ok_or(Errno::ENOTTY)?;
+        if data.len() < cmd.buffer_len() {
+            return Err(Errno::EINVAL);
+        }
+
+        handler(fs, data).map(|()| 0)
+    }
+}
+
+impl<F: ?Sized> Default for IoctlRegistry<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for IoctlRegistry<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(self.handlers.keys().map(|cmd| IoctlCmd::from_raw(*cmd)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let cmd = IoctlCmd::read_write::<u32>(b'X', 7);
+        assert_eq!((cmd.ty(), cmd.nr(), cmd.size()), (b'X', 7, 4));
+        assert!(cmd.reads() && cmd.writes());
+        assert_eq!(IoctlCmd::from_raw(cmd.raw()), cmd);
+
+        let none = IoctlCmd::none(b'X', 1);
+        assert!(!none.reads() && !none.writes());
+        assert_eq!(none.size(), 0);
+
+        let write = IoctlCmd::write::<[u8; 100]>(b'X', 2);
+        assert!(!write.reads() && write.writes());
+        assert_eq!(write.size(), 100);
+    }
+
+    // The same numbers the C macros come up with
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn matches_c() {
+        // FS_IOC_GETFLAGS is _IOR('f', 1, long)
+        assert_eq!(IoctlCmd::read::<libc::c_long>(b'f', 1).raw(), 0x8008_6601);
+        // FICLONE is _IOW(0x94, 9, int)
+        assert_eq!(IoctlCmd::write::<libc::c_int>(0x94, 9).raw(), 0x4004_9409);
+    }
+
+    #[test]
+    fn dispatch() {
+        const GET: IoctlCmd = IoctlCmd::read::<u64>(b'D', 1);
+        const DOUBLE: IoctlCmd = IoctlCmd::read_write::<u32>(b'D', 2);
+        const UNKNOWN: IoctlCmd = IoctlCmd::none(b'D', 3);
+
+        let registry = IoctlRegistry::new()
+            .read(GET, |base: &u64| Ok(*base))
+            .read_write(DOUBLE, |_: &u64, n: u32| n.checked_mul(2).ok_or(Errno::ERANGE));
+
+        let mut data = [0u8; 8];
+        assert_eq!(registry.dispatch(&42, GET, &mut data), Ok(0));
+        assert_eq!(u64::from_ne_bytes(data), 42);
+
+        let mut data = 21u32.to_ne_bytes();
+        assert_eq!(registry.dispatch(&0, DOUBLE, &mut data), Ok(0));
+        assert_eq!(u32::from_ne_bytes(data), 42);
+
+        let mut data = u32::MAX.to_ne_bytes();
+        assert_eq!(registry.dispatch(&0, DOUBLE, &mut data), Err(Errno::ERANGE));
+
+        assert_eq!(registry.dispatch(&0, UNKNOWN, &mut []), Err(Errno::ENOTTY));
+        // Less than the command says there should be
+        assert_eq!(registry.dispatch(&0, GET, &mut [0; 4]), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    #[should_panic]
+    fn mismatched_registration() {
+        IoctlRegistry::<()>::new().write(IoctlCmd::read::<u32>(b'D', 1), |_, _: u32| Ok(()));
+    }
+
+    #[test]
+    fn buffers() {
+        let mut data = [0u8; 16];
+        let cmd = IoctlCmd::write::<u64>(b'B', 1);
+        assert_eq!(unsafe { buffer(data.as_mut_ptr() as *mut c_void, cmd) }.len(), 8);
+        assert!(unsafe { buffer(ptr::null_mut(), cmd) }.is_empty());
+        assert!(unsafe { buffer(data.as_mut_ptr() as *mut c_void, IoctlCmd::none(b'B', 2)) }.is_empty());
+    }
+}
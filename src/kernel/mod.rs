@@ -0,0 +1,276 @@
+//! A pure rust replacement for the parts of libfuse that the generated code relies on.
+//!
+//! Instead of handing our `fuse_operations` to libfuse, [`fuse_main_real`] mounts
+//! through `fusermount`, reads requests straight off of `/dev/fuse` and calls into the
+//! operations itself. Nothing here needs the C library or its headers, so it can be
+//! built for static musl binaries and cross compiled without a C toolchain.
+//!
+//! Compared to libfuse, requests are always handled one at a time and the process
+//! never daemonizes, so `-f` and `-s` are implied. Of the high level library's own
+//! `-o` options only `entry_timeout`, `attr_timeout`, `uid`, `gid` and `umask` are
+//! understood, everything else goes to fusermount. `lock`, `bmap`, `ioctl` and `poll`
+//! aren't negotiated with the kernel.
+
+mod abi;
+mod session;
+
+use std::{
+    cell::UnsafeCell,
+    ffi::{CStr, OsStr, OsString},
+    io,
+    os::{
+        raw::{c_char, c_int, c_void},
+        unix::ffi::OsStrExt,
+    },
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{fuse, fuse_context, fuse_operations, fusermount};
+
+thread_local! {
+    static CONTEXT: UnsafeCell<fuse_context> = const { UnsafeCell::new(fuse_context {
+        fuse: ptr::null_mut(),
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        private_data: ptr::null_mut(),
+        umask: 0,
+    }) };
+}
+
+/// Gets the context of the request currently being handled on this thread.
+pub fn fuse_get_context() -> *mut fuse_context {
+    CONTEXT.with(|context| context.get())
+}
+
+// The context's fuse is the exiting flag of the loop the request came from, for fuse_exit
+fn set_context(uid: u32, gid: u32, pid: u32, exiting: *const AtomicBool, private_data: *mut c_void) {
+    CONTEXT.with(|context| unsafe {
+        let context = &mut *context.get();
+        context.fuse = exiting as *mut fuse;
+        context.uid = uid;
+        context.gid = gid;
+        context.pid = pid as _;
+        context.private_data = private_data;
+        context.umask = 0;
+    });
+}
+
+// Only the requests that create something come with a umask
+fn set_umask(umask: u32) {
+    CONTEXT.with(|context| unsafe {
+        (*context.get()).umask = umask as _;
+    });
+}
+
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+/// Stops the loop `f` belongs to once the request being handled has been answered,
+/// or the one started by fuse_main_real if `f` is null.
+///
+/// # Safety
+/// `f` has to be null or come from the context of a request that's being handled.
+pub unsafe fn fuse_exit(f: *mut fuse) {
+    match (f as *const AtomicBool).as_ref() {
+        Some(exiting) => exiting.store(true, Ordering::SeqCst),
+        None => EXITING.store(true, Ordering::SeqCst),
+    }
+}
+
+extern "C" fn exit_handler(_signal: c_int) {
+    EXITING.store(true, Ordering::SeqCst);
+}
+
+const EXIT_SIGNALS: [c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+// Like libfuse, SIGINT, SIGTERM and SIGHUP stop the loop and unmount.
+// SA_RESTART is left off so that the blocking read on /dev/fuse gets interrupted.
+fn set_signal_handlers() -> Vec<libc::sigaction> {
+    EXIT_SIGNALS
+        .iter()
+        .map(|&signal| unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = exit_handler as extern "C" fn(c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut old = std::mem::zeroed();
+            libc::sigaction(signal, &action, &mut old);
+            old
+        })
+        .collect()
+}
+
+fn remove_signal_handlers(old: Vec<libc::sigaction>) {
+    for (&signal, old) in EXIT_SIGNALS.iter().zip(old) {
+        unsafe { libc::sigaction(signal, &old, ptr::null_mut()) };
+    }
+}
+
+struct Args {
+    mountpoint: Option<OsString>,
+    options: Vec<String>,
+    config: session::Config,
+}
+
+// Library options that don't parse get passed on, so fusermount can complain about them
+fn library_option(config: &mut session::Config, option: &str) -> bool {
+    let (name, value) = match option.split_once('=') {
+        Some(option) => option,
+        None => return false,
+    };
+    let timeout = || value.parse().ok().filter(|secs: &f64| *secs >= 0.0).map(Duration::from_secs_f64);
+
+    match name {
+        "entry_timeout" => timeout().map(|t| config.entry_timeout = t).is_some(),
+        "attr_timeout" => timeout().map(|t| config.attr_timeout = t).is_some(),
+        "uid" => value.parse().map(|uid| config.uid = Some(uid)).is_ok(),
+        "gid" => value.parse().map(|gid| config.gid = Some(gid)).is_ok(),
+        "umask" => u32::from_str_radix(value, 8).map(|umask| config.umask = Some(umask)).is_ok(),
+        _ => false,
+    }
+}
+
+impl Args {
+    fn parse(argv: &[&CStr]) -> Self {
+        let mut args = Self {
+            mountpoint: None,
+            options: vec![],
+            config: session::Config::default(),
+        };
+
+        let add_options = |args: &mut Self, options: &CStr| {
+            for option in options.to_string_lossy().split(',') {
+                match option {
+                    "" => {}
+                    "debug" => args.config.debug = true,
+                    option if library_option(&mut args.config, option) => {}
+                    option => args.options.push(option.to_owned()),
+                }
+            }
+        };
+
+        // The first argument is the program name
+        let mut argv = argv.iter().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.to_bytes() {
+                b"-d" => args.config.debug = true,
+                b"-f" | b"-s" => {}
+                b"-o" => {
+                    if let Some(options) = argv.next() {
+                        add_options(&mut args, options)
+                    }
+                }
+                [b'-', b'o', ..] => {
+                    let options = CStr::from_bytes_with_nul(&arg.to_bytes_with_nul()[2..]).unwrap();
+                    add_options(&mut args, options)
+                }
+                [b'-', ..] => eprintln!(
+                    "fuse: ignoring unsupported option {}",
+                    arg.to_string_lossy()
+                ),
+                mountpoint if args.mountpoint.is_none() => {
+                    args.mountpoint = Some(OsStr::from_bytes(mountpoint).to_owned())
+                }
+                _ => eprintln!("fuse: invalid argument `{}'", arg.to_string_lossy()),
+            }
+        }
+
+        args
+    }
+}
+
+/// A mounted filesystem that hasn't started serving requests yet.
+pub(crate) struct Mount {
+    fd: c_int,
+    mountpoint: OsString,
+    session: session::Session,
+}
+
+// Everything in the session belongs to whichever thread runs it
+unsafe impl Send for Mount {}
+
+impl Mount {
+    /// Mounts the filesystem with the same arguments fuse_main_real takes.
+    pub unsafe fn new(
+        argv: &[&CStr],
+        op: &fuse_operations,
+        user_data: *mut c_void,
+    ) -> io::Result<Self> {
+        let args = Args::parse(argv);
+
+        let mountpoint = args
+            .mountpoint
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no mount point"))?;
+        let fd = fusermount::mount(&mountpoint, &args.options).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to mount {}: {e}", mountpoint.to_string_lossy()),
+            )
+        })?;
+
+        Ok(Self {
+            fd,
+            mountpoint,
+            session: session::Session::new(fd, *op, user_data, args.config),
+        })
+    }
+
+    /// Serves requests until the filesystem gets unmounted or `exiting` is set,
+    /// then makes sure it's unmounted.
+    pub unsafe fn run(mut self, exiting: &AtomicBool) -> c_int {
+        let out = match self.session.run(exiting) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("fuse: reading device: {e}");
+                1
+            }
+        };
+
+        // If the kernel unmounted us this fails quietly, since there's nothing left to unmount
+        let _ = fusermount::unmount(&self.mountpoint);
+        libc::close(self.fd);
+
+        out
+    }
+
+    /// Like [`Mount::run`], except that SIGINT, SIGTERM and SIGHUP stop the loop too,
+    /// the way they do for fuse_main.
+    pub unsafe fn run_until_signalled(self) -> c_int {
+        EXITING.store(false, Ordering::SeqCst);
+        let old_handlers = set_signal_handlers();
+
+        let out = self.run(&EXITING);
+
+        remove_signal_handlers(old_handlers);
+        out
+    }
+}
+
+/// Mounts and serves the filesystem described by `op` until it's unmounted,
+/// with the same arguments and return value as libfuse's version.
+///
+/// # Safety
+/// `argv` has to hold `argc` valid C strings and `op` has to point to a `fuse_operations`.
+pub unsafe fn fuse_main_real(
+    argc: c_int,
+    argv: *mut *mut c_char,
+    op: *const fuse_operations,
+    _op_size: usize,
+    user_data: *mut c_void,
+) -> c_int {
+    let argv: Vec<&CStr> = (0..argc as usize)
+        .map(|i| CStr::from_ptr(*argv.add(i)))
+        .collect();
+
+    let mount = match Mount::new(&argv, &*op, user_data) {
+        Ok(mount) => mount,
+        Err(e) => {
+            eprintln!("fuse: {e}");
+            return 1;
+        }
+    };
+
+    mount.run_until_signalled()
+}
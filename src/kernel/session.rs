@@ -0,0 +1,1117 @@
+//! Answers the requests read off of /dev/fuse by calling into a `fuse_operations`.
+//!
+//! The kernel addresses everything by node id while the high level api works with paths,
+//! so like libfuse we keep a table of every node the kernel has looked up and rebuild
+//! paths from it.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, OsStr, OsString},
+    io, mem,
+    os::{
+        raw::{c_char, c_int, c_void},
+        unix::{ffi::OsStrExt, io::RawFd},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use super::{abi::*, set_context, set_umask};
+use crate::{flock, fuse_conn_info, fuse_file_info, fuse_operations, off_t, stat, statvfs, timespec};
+
+const MAX_WRITE: u32 = 128 * 1024;
+// Big enough for the largest write plus its headers
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+/// The options libfuse's high level library would've handled, instead of fusermount.
+pub struct Config {
+    pub debug: bool,
+    pub entry_timeout: Duration,
+    pub attr_timeout: Duration,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub umask: Option<u32>,
+}
+
+impl Default for Config {
+    // libfuse's defaults
+    fn default() -> Self {
+        Self {
+            debug: false,
+            entry_timeout: Duration::from_secs(1),
+            attr_timeout: Duration::from_secs(1),
+            uid: None,
+            gid: None,
+            umask: None,
+        }
+    }
+}
+
+// Calls an operation, failing with ENOSYS when it isn't set. The requests libfuse
+// answers some other way without one handle that themselves
+macro_rules! call {
+    ($session:ident.$op:ident($($arg:expr),* $(,)?)) => {
+        match $session.ops.$op {
+            Some(op) => unsafe { op($($arg),*) },
+            None => -libc::ENOSYS,
+        }
+    };
+}
+
+struct Node {
+    parent: u64,
+    name: OsString,
+    nlookup: u64,
+}
+
+struct Nodes {
+    nodes: HashMap<u64, Node>,
+    names: HashMap<(u64, OsString), u64>,
+    next: u64,
+}
+
+impl Nodes {
+    fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            names: HashMap::new(),
+            next: FUSE_ROOT_ID + 1,
+        }
+    }
+
+    fn path(&self, mut ino: u64) -> Option<CString> {
+        let mut names = vec![];
+        while ino != FUSE_ROOT_ID {
+            let node = self.nodes.get(&ino)?;
+            names.push(node.name.as_bytes());
+            ino = node.parent;
+        }
+
+        if names.is_empty() {
+            return CString::new("/").ok();
+        }
+
+        let mut path = vec![];
+        for name in names.into_iter().rev() {
+            path.push(b'/');
+            path.extend_from_slice(name);
+        }
+
+        CString::new(path).ok()
+    }
+
+    fn child_path(&self, parent: u64, name: &CStr) -> Option<CString> {
+        let mut path = self.path(parent)?.into_bytes();
+        if path != b"/" {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name.to_bytes());
+
+        CString::new(path).ok()
+    }
+
+    /// Finds or creates the node for `name` in `parent` and counts the kernel's new reference to it.
+    fn lookup(&mut self, parent: u64, name: &CStr) -> u64 {
+        let key = (parent, OsStr::from_bytes(name.to_bytes()).to_owned());
+        let ino = match self.names.get(&key) {
+            Some(&ino) => ino,
+            None => {
+                let ino = self.next;
+                self.next += 1;
+
+                self.nodes.insert(
+                    ino,
+                    Node {
+                        parent,
+                        name: key.1.clone(),
+                        nlookup: 0,
+                    },
+                );
+                self.names.insert(key, ino);
+                ino
+            }
+        };
+
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.nlookup += 1;
+        }
+        ino
+    }
+
+    fn forget(&mut self, ino: u64, nlookup: u64) {
+        let node = match self.nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => return,
+        };
+
+        node.nlookup = node.nlookup.saturating_sub(nlookup);
+        if node.nlookup == 0 {
+            let node = self.nodes.remove(&ino).unwrap();
+            let key = (node.parent, node.name);
+            if self.names.get(&key) == Some(&ino) {
+                self.names.remove(&key);
+            }
+        }
+    }
+
+    /// Stops `name` from resolving to a node. The node itself stays around
+    /// until the kernel forgets it, since it may still be open.
+    fn remove(&mut self, parent: u64, name: &CStr) {
+        self.names
+            .remove(&(parent, OsStr::from_bytes(name.to_bytes()).to_owned()));
+    }
+
+    fn rename(&mut self, parent: u64, name: &CStr, new_parent: u64, new_name: &CStr) {
+        let new_key = (
+            new_parent,
+            OsStr::from_bytes(new_name.to_bytes()).to_owned(),
+        );
+        self.names.remove(&new_key);
+
+        let ino = match self
+            .names
+            .remove(&(parent, OsStr::from_bytes(name.to_bytes()).to_owned()))
+        {
+            Some(ino) => ino,
+            None => return,
+        };
+
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.parent = new_parent;
+            node.name = new_key.1.clone();
+        }
+        self.names.insert(new_key, ino);
+    }
+}
+
+fn as_bytes<T>(t: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(t as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn attr(ino: u64, stat: &stat) -> fuse_attr {
+    fuse_attr {
+        ino,
+        size: stat.st_size as u64,
+        blocks: stat.st_blocks as u64,
+        atime: stat.st_atim.tv_sec as u64,
+        mtime: stat.st_mtim.tv_sec as u64,
+        ctime: stat.st_ctim.tv_sec as u64,
+        atimensec: stat.st_atim.tv_nsec as u32,
+        mtimensec: stat.st_mtim.tv_nsec as u32,
+        ctimensec: stat.st_ctim.tv_nsec as u32,
+        mode: stat.st_mode,
+        nlink: stat.st_nlink as u32,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        rdev: stat.st_rdev as u32,
+        blksize: stat.st_blksize as u32,
+        padding: 0,
+    }
+}
+
+fn open_flags(info: &fuse_file_info) -> u32 {
+    let mut flags = 0;
+    if info.direct_io() != 0 {
+        flags |= FOPEN_DIRECT_IO;
+    }
+    if info.keep_cache() != 0 {
+        flags |= FOPEN_KEEP_CACHE;
+    }
+    if info.nonseekable() != 0 {
+        flags |= FOPEN_NONSEEKABLE;
+    }
+    flags
+}
+
+/// Pulls the arguments of a request out one at a time.
+struct Args<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn fetch<T: Copy>(&mut self) -> Option<T> {
+        if self.data.len() < mem::size_of::<T>() {
+            return None;
+        }
+
+        let out = unsafe { (self.data.as_ptr() as *const T).read_unaligned() };
+        self.data = &self.data[mem::size_of::<T>()..];
+        Some(out)
+    }
+
+    fn fetch_str(&mut self) -> Option<&'a CStr> {
+        let end = self.data.iter().position(|&b| b == 0)?;
+        let out = CStr::from_bytes_with_nul(&self.data[..=end]).ok()?;
+        self.data = &self.data[end + 1..];
+        Some(out)
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// What readdir's filler writes into. Filesystems that don't do their own offsets
+/// get numbered here, so that every request can skip what's already been listed.
+struct DirBuffer {
+    buf: Vec<u8>,
+    size: usize,
+    offset: u64,
+    index: u64,
+}
+
+unsafe extern "C" fn fill_dir(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+) -> c_int {
+    let dir = &mut *(buf as *mut DirBuffer);
+
+    let off = if off == 0 {
+        dir.index += 1;
+        if dir.index <= dir.offset {
+            return 0;
+        }
+        dir.index
+    } else {
+        off as u64
+    };
+
+    let name = CStr::from_ptr(name).to_bytes();
+    let (ino, mode) = match stbuf.as_ref() {
+        Some(stat) if stat.st_ino != 0 => (stat.st_ino, stat.st_mode),
+        Some(stat) => (FUSE_UNKNOWN_INO, stat.st_mode),
+        None => (FUSE_UNKNOWN_INO, 0),
+    };
+
+    let dirent = fuse_dirent {
+        ino,
+        off,
+        namelen: name.len() as u32,
+        type_: (mode & libc::S_IFMT) >> 12,
+    };
+
+    let len = mem::size_of::<fuse_dirent>() + name.len();
+    let padded = (len + 7) & !7;
+    if dir.buf.len() + padded > dir.size {
+        return 1;
+    }
+
+    dir.buf.extend_from_slice(as_bytes(&dirent));
+    dir.buf.extend_from_slice(name);
+    dir.buf.resize(dir.buf.len() + padded - len, 0);
+    0
+}
+
+pub struct Session {
+    fd: RawFd,
+    ops: fuse_operations,
+    user_data: *mut c_void,
+    nodes: Nodes,
+    config: Config,
+    exiting: *const AtomicBool,
+    destroyed: bool,
+}
+
+impl Session {
+    pub fn new(fd: RawFd, ops: fuse_operations, user_data: *mut c_void, config: Config) -> Self {
+        Self {
+            fd,
+            ops,
+            user_data,
+            nodes: Nodes::new(),
+            config,
+            exiting: std::ptr::null(),
+            destroyed: false,
+        }
+    }
+
+    /// Handles requests until the filesystem gets unmounted or `exiting` is set.
+    pub fn run(&mut self, exiting: &AtomicBool) -> io::Result<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        self.exiting = exiting;
+
+        while !exiting.load(Ordering::SeqCst) && !self.destroyed {
+            let read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            if read < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    // The request we were about to read got interrupted
+                    Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                    // We've been unmounted
+                    Some(libc::ENODEV) => break,
+                    _ => return Err(e),
+                }
+            }
+
+            self.dispatch(&buf[..read as usize]);
+        }
+
+        if !self.destroyed {
+            set_context(0, 0, 0, self.exiting, self.user_data);
+            if let Some(destroy) = self.ops.destroy {
+                unsafe { destroy(self.user_data) };
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reply(&self, unique: u64, error: c_int, data: &[&[u8]]) {
+        let len = mem::size_of::<fuse_out_header>() + data.iter().map(|d| d.len()).sum::<usize>();
+        let header = fuse_out_header {
+            len: len as u32,
+            error: -error,
+            unique,
+        };
+
+        let iov: Vec<libc::iovec> = [as_bytes(&header)]
+            .iter()
+            .chain(data)
+            .map(|d| libc::iovec {
+                iov_base: d.as_ptr() as *mut c_void,
+                iov_len: d.len(),
+            })
+            .collect();
+
+        // ENOENT means the request was interrupted and nobody is waiting for the answer anymore
+        if unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as c_int) } == -1 && self.config.debug {
+            eprintln!(
+                "fuse: writing reply to {unique}: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn reply_err(&self, unique: u64, error: c_int) {
+        self.reply(unique, error, &[]);
+    }
+
+    fn reply_ok<T>(&self, unique: u64, out: &T) {
+        self.reply(unique, 0, &[as_bytes(out)]);
+    }
+
+    fn reply_empty(&self, unique: u64, res: c_int) {
+        self.reply_err(unique, if res < 0 { -res } else { 0 });
+    }
+
+    fn getattr(&self, path: &CStr, info: Option<&mut fuse_file_info>) -> Result<stat, c_int> {
+        let mut stat = stat::default();
+
+        let mut res = -libc::ENOSYS;
+        if let Some(info) = info {
+            res = call!(self.fgetattr(path.as_ptr(), &mut stat, info));
+        }
+        if res == -libc::ENOSYS {
+            res = call!(self.getattr(path.as_ptr(), &mut stat));
+        }
+
+        if res < 0 {
+            return Err(-res);
+        }
+
+        if let Some(uid) = self.config.uid {
+            stat.st_uid = uid;
+        }
+        if let Some(gid) = self.config.gid {
+            stat.st_gid = gid;
+        }
+        if let Some(umask) = self.config.umask {
+            stat.st_mode &= !(umask & 0o777);
+        }
+        Ok(stat)
+    }
+
+    fn entry_out(&mut self, parent: u64, name: &CStr) -> Result<fuse_entry_out, c_int> {
+        let path = self.nodes.child_path(parent, name).ok_or(libc::ENOENT)?;
+        let stat = self.getattr(&path, None)?;
+        let ino = self.nodes.lookup(parent, name);
+
+        Ok(fuse_entry_out {
+            nodeid: ino,
+            generation: 0,
+            entry_valid: self.config.entry_timeout.as_secs(),
+            attr_valid: self.config.attr_timeout.as_secs(),
+            entry_valid_nsec: self.config.entry_timeout.subsec_nanos(),
+            attr_valid_nsec: self.config.attr_timeout.subsec_nanos(),
+            attr: attr(ino, &stat),
+        })
+    }
+
+    fn reply_entry(&mut self, unique: u64, parent: u64, name: &CStr) {
+        match self.entry_out(parent, name) {
+            Ok(out) => self.reply_ok(unique, &out),
+            Err(e) => self.reply_err(unique, e),
+        }
+    }
+
+    fn reply_attr(&self, unique: u64, ino: u64, path: &CStr, info: Option<&mut fuse_file_info>) {
+        match self.getattr(path, info) {
+            Ok(stat) => self.reply_ok(
+                unique,
+                &fuse_attr_out {
+                    attr_valid: self.config.attr_timeout.as_secs(),
+                    attr_valid_nsec: self.config.attr_timeout.subsec_nanos(),
+                    dummy: 0,
+                    attr: attr(ino, &stat),
+                },
+            ),
+            Err(e) => self.reply_err(unique, e),
+        }
+    }
+
+    fn dispatch(&mut self, request: &[u8]) {
+        let mut args = Args { data: request };
+        let header: fuse_in_header = match args.fetch() {
+            Some(header) => header,
+            None => return,
+        };
+        let unique = header.unique;
+
+        if self.config.debug {
+            eprintln!(
+                "unique: {}, opcode: {}, nodeid: {}, insize: {}",
+                unique,
+                header.opcode,
+                header.nodeid,
+                request.len()
+            );
+        }
+
+        set_context(header.uid, header.gid, header.pid, self.exiting, self.user_data);
+
+        // Requests that don't need the path of their node
+        match header.opcode {
+            FUSE_INIT => return self.init(unique, &mut args),
+            FUSE_DESTROY => {
+                if let Some(destroy) = self.ops.destroy {
+                    unsafe { destroy(self.user_data) };
+                }
+                self.destroyed = true;
+                return self.reply_err(unique, 0);
+            }
+            FUSE_FORGET => {
+                if let Some(forget) = args.fetch::<fuse_forget_in>() {
+                    self.nodes.forget(header.nodeid, forget.nlookup);
+                }
+                return;
+            }
+            FUSE_BATCH_FORGET => {
+                if let Some(batch) = args.fetch::<fuse_batch_forget_in>() {
+                    for _ in 0..batch.count {
+                        match args.fetch::<fuse_forget_one>() {
+                            Some(one) => self.nodes.forget(one.nodeid, one.nlookup),
+                            None => break,
+                        }
+                    }
+                }
+                return;
+            }
+            // We don't handle requests concurrently, so by the time we see one
+            // of these the request it's for has already been answered.
+            FUSE_INTERRUPT => return,
+            _ => {}
+        }
+
+        let path = match self.nodes.path(header.nodeid) {
+            Some(path) => path,
+            None => return self.reply_err(unique, libc::ENOENT),
+        };
+
+        macro_rules! fetch {
+            ($($fetch:tt)*) => {
+                match args.$($fetch)* {
+                    Some(arg) => arg,
+                    None => return self.reply_err(unique, libc::EINVAL),
+                }
+            };
+        }
+
+        macro_rules! child_path {
+            ($name:expr) => {
+                match self.nodes.child_path(header.nodeid, $name) {
+                    Some(path) => path,
+                    None => return self.reply_err(unique, libc::ENOENT),
+                }
+            };
+        }
+
+        match header.opcode {
+            FUSE_LOOKUP => {
+                let name = fetch!(fetch_str());
+                self.reply_entry(unique, header.nodeid, name)
+            }
+
+            FUSE_GETATTR => {
+                let getattr: fuse_getattr_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    fh: getattr.fh,
+                    ..Default::default()
+                };
+                let info = (getattr.getattr_flags & FUSE_GETATTR_FH != 0).then_some(&mut info);
+                self.reply_attr(unique, header.nodeid, &path, info)
+            }
+
+            FUSE_SETATTR => {
+                let setattr: fuse_setattr_in = fetch!(fetch());
+                self.setattr(unique, header.nodeid, &path, setattr)
+            }
+
+            FUSE_READLINK => {
+                let mut buf = vec![0u8; libc::PATH_MAX as usize + 1];
+                let res =
+                    call!(self.readlink(path.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len()));
+                if res < 0 {
+                    return self.reply_err(unique, -res);
+                }
+
+                let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+                self.reply(unique, 0, &[&buf[..len]])
+            }
+
+            FUSE_SYMLINK => {
+                let name = fetch!(fetch_str());
+                let link = fetch!(fetch_str());
+                let new = child_path!(name);
+
+                match call!(self.symlink(link.as_ptr(), new.as_ptr())) {
+                    res if res < 0 => self.reply_err(unique, -res),
+                    _ => self.reply_entry(unique, header.nodeid, name),
+                }
+            }
+
+            FUSE_MKNOD => {
+                let mknod: fuse_mknod_in = fetch!(fetch());
+                set_umask(mknod.umask);
+                let name = fetch!(fetch_str());
+                let new = child_path!(name);
+
+                match call!(self.mknod(new.as_ptr(), mknod.mode, mknod.rdev as _)) {
+                    res if res < 0 => self.reply_err(unique, -res),
+                    _ => self.reply_entry(unique, header.nodeid, name),
+                }
+            }
+
+            FUSE_MKDIR => {
+                let mkdir: fuse_mkdir_in = fetch!(fetch());
+                set_umask(mkdir.umask);
+                let name = fetch!(fetch_str());
+                let new = child_path!(name);
+
+                match call!(self.mkdir(new.as_ptr(), mkdir.mode)) {
+                    res if res < 0 => self.reply_err(unique, -res),
+                    _ => self.reply_entry(unique, header.nodeid, name),
+                }
+            }
+
+            FUSE_UNLINK | FUSE_RMDIR => {
+                let name = fetch!(fetch_str());
+                let child = child_path!(name);
+
+                let res = if header.opcode == FUSE_UNLINK {
+                    call!(self.unlink(child.as_ptr()))
+                } else {
+                    call!(self.rmdir(child.as_ptr()))
+                };
+                if res == 0 {
+                    self.nodes.remove(header.nodeid, name);
+                }
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_RENAME | FUSE_RENAME2 => {
+                let new_dir = if header.opcode == FUSE_RENAME {
+                    fetch!(fetch::<fuse_rename_in>()).newdir
+                } else {
+                    let rename: fuse_rename2_in = fetch!(fetch());
+                    // RENAME_NOREPLACE and RENAME_EXCHANGE need the fuse3 rename signature
+                    if rename.flags != 0 {
+                        return self.reply_err(unique, libc::EINVAL);
+                    }
+                    rename.newdir
+                };
+                let name = fetch!(fetch_str());
+                let new_name = fetch!(fetch_str());
+
+                let old = child_path!(name);
+                let new = match self.nodes.child_path(new_dir, new_name) {
+                    Some(path) => path,
+                    None => return self.reply_err(unique, libc::ENOENT),
+                };
+
+                let res = call!(self.rename(old.as_ptr(), new.as_ptr()));
+                if res == 0 {
+                    self.nodes.rename(header.nodeid, name, new_dir, new_name);
+                }
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_LINK => {
+                let link: fuse_link_in = fetch!(fetch());
+                let new_name = fetch!(fetch_str());
+
+                let old = match self.nodes.path(link.oldnodeid) {
+                    Some(path) => path,
+                    None => return self.reply_err(unique, libc::ENOENT),
+                };
+                let new = child_path!(new_name);
+
+                match call!(self.link(old.as_ptr(), new.as_ptr())) {
+                    res if res < 0 => self.reply_err(unique, -res),
+                    _ => self.reply_entry(unique, header.nodeid, new_name),
+                }
+            }
+
+            FUSE_OPEN | FUSE_OPENDIR => {
+                let open: fuse_open_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    flags: open.flags as c_int,
+                    ..Default::default()
+                };
+
+                let res = if header.opcode == FUSE_OPEN {
+                    call!(self.open(path.as_ptr(), &mut info))
+                } else {
+                    call!(self.opendir(path.as_ptr(), &mut info))
+                };
+                // libfuse opens anything without them, with a handle of 0
+                if res < 0 && res != -libc::ENOSYS {
+                    return self.reply_err(unique, -res);
+                }
+
+                self.reply_ok(
+                    unique,
+                    &fuse_open_out {
+                        fh: info.fh,
+                        open_flags: open_flags(&info),
+                        padding: 0,
+                    },
+                )
+            }
+
+            FUSE_READ => {
+                let read: fuse_read_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    flags: read.flags as c_int,
+                    fh: read.fh,
+                    lock_owner: read.lock_owner,
+                    ..Default::default()
+                };
+
+                let mut buf = vec![0u8; read.size as usize];
+                let res = call!(self.read(
+                    path.as_ptr(),
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                    read.offset as off_t,
+                    &mut info,
+                ));
+                if res < 0 {
+                    return self.reply_err(unique, -res);
+                }
+
+                self.reply(unique, 0, &[&buf[..(res as usize).min(buf.len())]])
+            }
+
+            FUSE_WRITE => {
+                let write: fuse_write_in = fetch!(fetch());
+                let data = args.rest();
+                let data = &data[..(write.size as usize).min(data.len())];
+
+                let mut info = fuse_file_info {
+                    flags: write.flags as c_int,
+                    fh: write.fh,
+                    lock_owner: write.lock_owner,
+                    writepage: (write.write_flags & FUSE_WRITE_CACHE != 0) as c_int,
+                    ..Default::default()
+                };
+
+                let res = call!(self.write(
+                    path.as_ptr(),
+                    data.as_ptr() as *const c_char,
+                    data.len(),
+                    write.offset as off_t,
+                    &mut info,
+                ));
+                if res < 0 {
+                    return self.reply_err(unique, -res);
+                }
+
+                self.reply_ok(
+                    unique,
+                    &fuse_write_out {
+                        size: res as u32,
+                        padding: 0,
+                    },
+                )
+            }
+
+            FUSE_STATFS => {
+                let mut statvfs = statvfs::default();
+                let st = match call!(self.statfs(path.as_ptr(), &mut statvfs)) {
+                    // Same defaults as libfuse
+                    res if res == -libc::ENOSYS => fuse_kstatfs {
+                        namelen: 255,
+                        bsize: 512,
+                        ..Default::default()
+                    },
+                    res if res < 0 => return self.reply_err(unique, -res),
+                    _ => fuse_kstatfs {
+                        blocks: statvfs.f_blocks,
+                        bfree: statvfs.f_bfree,
+                        bavail: statvfs.f_bavail,
+                        files: statvfs.f_files,
+                        ffree: statvfs.f_ffree,
+                        bsize: statvfs.f_bsize as u32,
+                        namelen: statvfs.f_namemax as u32,
+                        frsize: statvfs.f_frsize as u32,
+                        ..Default::default()
+                    },
+                };
+
+                self.reply_ok(unique, &fuse_statfs_out { st })
+            }
+
+            FUSE_RELEASE | FUSE_RELEASEDIR => {
+                let release: fuse_release_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    flags: release.flags as c_int,
+                    fh: release.fh,
+                    lock_owner: release.lock_owner,
+                    ..Default::default()
+                };
+                info.set_flush((release.release_flags & FUSE_RELEASE_FLUSH != 0) as u32);
+
+                // The kernel stops sending flushes once one fails with ENOSYS, and asks
+                // for one here instead
+                if info.flush() != 0 {
+                    self.flush(&path, &mut info);
+                }
+
+                let res = if header.opcode == FUSE_RELEASE {
+                    call!(self.release(path.as_ptr(), &mut info))
+                } else {
+                    call!(self.releasedir(path.as_ptr(), &mut info))
+                };
+                self.reply_empty(unique, if res == -libc::ENOSYS { 0 } else { res })
+            }
+
+            FUSE_FSYNC | FUSE_FSYNCDIR => {
+                let fsync: fuse_fsync_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    fh: fsync.fh,
+                    ..Default::default()
+                };
+                let datasync = (fsync.fsync_flags & FUSE_FSYNC_FDATASYNC != 0) as c_int;
+
+                let res = if header.opcode == FUSE_FSYNC {
+                    call!(self.fsync(path.as_ptr(), datasync, &mut info))
+                } else {
+                    call!(self.fsyncdir(path.as_ptr(), datasync, &mut info))
+                };
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_FLUSH => {
+                let flush: fuse_flush_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    fh: flush.fh,
+                    lock_owner: flush.lock_owner,
+                    ..Default::default()
+                };
+                info.set_flush(1);
+
+                let res = self.flush(&path, &mut info);
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_SETXATTR => {
+                let setxattr: fuse_setxattr_in = fetch!(fetch());
+                let name = fetch!(fetch_str());
+                let value = args.rest();
+                let value = &value[..(setxattr.size as usize).min(value.len())];
+
+                let res = call!(self.setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const c_char,
+                    value.len(),
+                    setxattr.flags as c_int,
+                ));
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_GETXATTR | FUSE_LISTXATTR => {
+                let getxattr: fuse_getxattr_in = fetch!(fetch());
+                let mut buf = vec![0u8; getxattr.size as usize];
+
+                let res = if header.opcode == FUSE_GETXATTR {
+                    let name = fetch!(fetch_str());
+                    call!(self.getxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        buf.as_mut_ptr() as *mut c_char,
+                        buf.len(),
+                    ))
+                } else {
+                    call!(self.listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len()))
+                };
+
+                if res < 0 {
+                    self.reply_err(unique, -res)
+                } else if buf.is_empty() {
+                    self.reply_ok(
+                        unique,
+                        &fuse_getxattr_out {
+                            size: res as u32,
+                            padding: 0,
+                        },
+                    )
+                } else {
+                    self.reply(unique, 0, &[&buf[..(res as usize).min(buf.len())]])
+                }
+            }
+
+            FUSE_REMOVEXATTR => {
+                let name = fetch!(fetch_str());
+                let res = call!(self.removexattr(path.as_ptr(), name.as_ptr()));
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_READDIR => {
+                let read: fuse_read_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    fh: read.fh,
+                    ..Default::default()
+                };
+
+                let mut dir = DirBuffer {
+                    buf: Vec::with_capacity(read.size as usize),
+                    size: read.size as usize,
+                    offset: read.offset,
+                    index: 0,
+                };
+
+                let res = call!(self.readdir(
+                    path.as_ptr(),
+                    &mut dir as *mut DirBuffer as *mut c_void,
+                    Some(fill_dir),
+                    read.offset as off_t,
+                    &mut info,
+                ));
+                if res < 0 {
+                    return self.reply_err(unique, -res);
+                }
+
+                self.reply(unique, 0, &[&dir.buf])
+            }
+
+            FUSE_ACCESS => {
+                let access: fuse_access_in = fetch!(fetch());
+                let res = call!(self.access(path.as_ptr(), access.mask as c_int));
+                self.reply_empty(unique, res)
+            }
+
+            FUSE_CREATE => {
+                let create: fuse_create_in = fetch!(fetch());
+                set_umask(create.umask);
+                let name = fetch!(fetch_str());
+                let new = child_path!(name);
+
+                let mut info = fuse_file_info {
+                    flags: create.flags as c_int,
+                    ..Default::default()
+                };
+
+                let res = call!(self.create(new.as_ptr(), create.mode, &mut info));
+                if res < 0 {
+                    // On ENOSYS the kernel falls back to mknod and open
+                    return self.reply_err(unique, -res);
+                }
+
+                match self.entry_out(header.nodeid, name) {
+                    Ok(entry) => {
+                        let open = fuse_open_out {
+                            fh: info.fh,
+                            open_flags: open_flags(&info),
+                            padding: 0,
+                        };
+                        self.reply(unique, 0, &[as_bytes(&entry), as_bytes(&open)])
+                    }
+                    Err(e) => {
+                        call!(self.release(new.as_ptr(), &mut info));
+                        self.reply_err(unique, e)
+                    }
+                }
+            }
+
+            FUSE_FALLOCATE => {
+                let fallocate: fuse_fallocate_in = fetch!(fetch());
+                let mut info = fuse_file_info {
+                    fh: fallocate.fh,
+                    ..Default::default()
+                };
+
+                let res = call!(self.fallocate(
+                    path.as_ptr(),
+                    fallocate.mode as c_int,
+                    fallocate.offset as off_t,
+                    fallocate.length as off_t,
+                    &mut info,
+                ));
+                self.reply_empty(unique, res)
+            }
+
+            _ => self.reply_err(unique, libc::ENOSYS),
+        }
+    }
+
+    fn init(&mut self, unique: u64, args: &mut Args) {
+        let init: fuse_init_in = match args.fetch() {
+            Some(init) => init,
+            None => return self.reply_err(unique, libc::EINVAL),
+        };
+
+        if init.major != FUSE_KERNEL_VERSION || init.minor < FUSE_KERNEL_MINOR_VERSION {
+            eprintln!(
+                "fuse: unsupported kernel protocol version {}.{}",
+                init.major, init.minor
+            );
+            return self.reply_err(unique, libc::EPROTO);
+        }
+
+        // The low capability bits in fuse_conn_info line up with the kernel's init flags
+        let mut conn = fuse_conn_info {
+            proto_major: FUSE_KERNEL_VERSION,
+            proto_minor: FUSE_KERNEL_MINOR_VERSION,
+            async_read: (init.flags & FUSE_ASYNC_READ != 0) as u32,
+            max_write: MAX_WRITE,
+            max_readahead: init.max_readahead,
+            capable: init.flags & (FUSE_ASYNC_READ | FUSE_BIG_WRITES),
+            want: init.flags & FUSE_BIG_WRITES,
+            ..Default::default()
+        };
+
+        if let Some(init) = self.ops.init {
+            self.user_data = unsafe { init(&mut conn) };
+        }
+
+        let mut flags = conn.want & conn.capable;
+        if conn.async_read != 0 {
+            flags |= init.flags & FUSE_ASYNC_READ;
+        }
+
+        self.reply_ok(
+            unique,
+            &fuse_init_out {
+                major: FUSE_KERNEL_VERSION,
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                max_readahead: conn.max_readahead.min(init.max_readahead),
+                flags,
+                max_background: conn.max_background as u16,
+                congestion_threshold: conn.congestion_threshold as u16,
+                max_write: conn.max_write.min(MAX_WRITE),
+            },
+        )
+    }
+
+    // libfuse drops the owner's byte range locks on every flush, and with a `lock` there's
+    // always something for a flush to do, so not having one isn't an error then
+    fn flush(&mut self, path: &CStr, info: &mut fuse_file_info) -> c_int {
+        let res = call!(self.flush(path.as_ptr(), info));
+        if self.ops.lock.is_none() {
+            return res;
+        }
+
+        let mut unlock = flock {
+            l_type: libc::F_UNLCK as _,
+            l_whence: libc::SEEK_SET as _,
+            l_start: 0,
+            l_len: 0,
+            l_pid: 0,
+        };
+        call!(self.lock(path.as_ptr(), info, libc::F_SETLK, &mut unlock));
+        if res == -libc::ENOSYS {
+            0
+        } else {
+            res
+        }
+    }
+
+    // Mirrors how libfuse splits a setattr into the individual high level operations
+    fn setattr(&mut self, unique: u64, ino: u64, path: &CStr, setattr: fuse_setattr_in) {
+        let valid = setattr.valid;
+        let mut info = fuse_file_info {
+            fh: setattr.fh,
+            lock_owner: setattr.lock_owner,
+            ..Default::default()
+        };
+        let has_fh = valid & FATTR_FH != 0;
+
+        let mut res = 0;
+
+        if valid & FATTR_MODE != 0 {
+            res = call!(self.chmod(path.as_ptr(), setattr.mode));
+        }
+
+        if res == 0 && valid & (FATTR_UID | FATTR_GID) != 0 {
+            let uid = if valid & FATTR_UID != 0 {
+                setattr.uid
+            } else {
+                u32::MAX
+            };
+            let gid = if valid & FATTR_GID != 0 {
+                setattr.gid
+            } else {
+                u32::MAX
+            };
+            res = call!(self.chown(path.as_ptr(), uid, gid));
+        }
+
+        if res == 0 && valid & FATTR_SIZE != 0 {
+            res = -libc::ENOSYS;
+            if has_fh {
+                res = call!(self.ftruncate(path.as_ptr(), setattr.size as off_t, &mut info));
+            }
+            if res == -libc::ENOSYS {
+                res = call!(self.truncate(path.as_ptr(), setattr.size as off_t));
+            }
+        }
+
+        if res == 0 && valid & (FATTR_ATIME | FATTR_MTIME) != 0 {
+            let time = |set, now, sec: u64, nsec: u32| timespec {
+                tv_sec: sec as _,
+                tv_nsec: if valid & now != 0 {
+                    libc::UTIME_NOW
+                } else if valid & set != 0 {
+                    nsec as _
+                } else {
+                    libc::UTIME_OMIT
+                },
+            };
+
+            let tv = [
+                time(
+                    FATTR_ATIME,
+                    FATTR_ATIME_NOW,
+                    setattr.atime,
+                    setattr.atimensec,
+                ),
+                time(
+                    FATTR_MTIME,
+                    FATTR_MTIME_NOW,
+                    setattr.mtime,
+                    setattr.mtimensec,
+                ),
+            ];
+            res = call!(self.utimens(path.as_ptr(), tv.as_ptr()));
+        }
+
+        if res < 0 {
+            return self.reply_err(unique, -res);
+        }
+
+        self.reply_attr(unique, ino, path, has_fh.then_some(&mut info))
+    }
+}
@@ -2,4 +2,68 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+#[cfg(all(feature = "pure_rust", feature = "fuse3"))]
+compile_error!("pure_rust implements the fuse2 api, it can't be combined with fuse3");
+
+#[cfg(all(feature = "pure_rust", not(target_os = "linux")))]
+compile_error!("pure_rust only supports linux's /dev/fuse");
+
+#[cfg(all(feature = "pure_rust", feature = "tokio"))]
+compile_error!("the async filesystem sits on the low level api, which pure_rust doesn't have");
+
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+#[cfg(feature = "auto")]
+pub use filesystem_macro::filesystem;
+
+mod conn;
+pub use conn::ConnInfo;
+
+mod dir;
+pub use dir::DirFiller;
+
+mod request;
+pub use request::Request;
+
+mod mount;
+pub use mount::{MountError, MountOptions, MountOptionsBuilder, MountOptionsError};
+
+mod threading;
+pub use threading::ThreadingConfig;
+
+mod fusermount;
+
+#[cfg(feature = "auto")]
+mod session;
+#[cfg(feature = "auto")]
+pub use session::{spawn_mount, BackgroundSession, ShutdownHandle};
+
+#[cfg(all(feature = "auto", not(feature = "pure_rust")))]
+mod workers;
+
+mod errno;
+pub use errno::{fallback_errno, set_fallback_errno, Errno, ToErrno};
+
+pub mod unwind;
+
+mod ioctl;
+pub use ioctl::{IoctlCmd, IoctlPayload, IoctlRegistry};
+
+mod lock;
+pub use lock::{Lock, LockKind, LockManager, LockRequest};
+
+mod poll;
+pub use poll::PollHandle;
+
+mod types;
+pub use types::{FileAttr, OpenReply, StatFs, TimeOrNow, XattrFlags};
+
+#[cfg(feature = "pure_rust")]
+mod kernel;
+
+#[cfg(not(feature = "pure_rust"))]
+pub mod reply;
+
+#[cfg(all(feature = "tokio", not(feature = "pure_rust")))]
+#[doc(hidden)]
+pub mod tasks;
@@ -0,0 +1,504 @@
+//! Advisory locks, both the `fcntl` byte range kind and `flock`'s whole file kind.
+//!
+//! The kernel only passes them along when a filesystem implements `lock` or `flock`,
+//! otherwise it keeps track of them itself, which is fine as long as every process
+//! using the filesystem is on the same machine. [`LockManager`] does that same
+//! bookkeeping for a filesystem that wants to share it with something else, or
+//! that just needs a starting point.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    hash::Hash,
+    os::raw::c_int,
+    sync::{Condvar, Mutex, MutexGuard},
+};
+
+use crate::{flock, Errno};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    /// Shared, any number of owners can hold one over the same bytes.
+    Read,
+    /// Exclusive.
+    Write,
+    Unlock,
+}
+
+impl LockKind {
+    // Read locks only get in the way of write locks
+    fn conflicts(self, other: Self) -> bool {
+        matches!((self, other), (Self::Write, Self::Read | Self::Write) | (Self::Read, Self::Write))
+    }
+}
+
+/// A lock over the bytes from `start` up to but not including `end`. A lock to the
+/// end of the file, however far that ends up being, ends at `u64::MAX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lock {
+    pub kind: LockKind,
+    pub start: u64,
+    pub end: u64,
+    /// The process holding it, which only matters when it gets reported back to someone else.
+    pub pid: libc::pid_t,
+}
+
+impl Lock {
+    /// Both libfuse and the kernel always hand over locks relative to the start of the file.
+    /// `None` if it's not a read, write or unlock.
+    pub fn from_flock(lock: &flock) -> Option<Self> {
+        let kind = match lock.l_type as c_int {
+            libc::F_RDLCK => LockKind::Read,
+            libc::F_WRLCK => LockKind::Write,
+            libc::F_UNLCK => LockKind::Unlock,
+            _ => return None,
+        };
+
+        let start = lock.l_start.max(0) as u64;
+        let (start, end) = match lock.l_len {
+            0 => (start, u64::MAX),
+            // Negative lengths go backwards from the start
+            len if len < 0 => (start.saturating_sub(len.unsigned_abs()), start),
+            len => (start, start.saturating_add(len as u64)),
+        };
+
+        Some(Self {
+            kind,
+            start,
+            end,
+            pid: lock.l_pid,
+        })
+    }
+
+    pub fn to_flock(&self) -> flock {
+        let l_type = match self.kind {
+            LockKind::Read => libc::F_RDLCK,
+            LockKind::Write => libc::F_WRLCK,
+            LockKind::Unlock => libc::F_UNLCK,
+        };
+        let l_len = if self.end == u64::MAX {
+            0
+        } else {
+            self.end.saturating_sub(self.start)
+        };
+
+        flock {
+            l_type: l_type as _,
+            l_whence: libc::SEEK_SET as _,
+            l_start: self.start.min(i64::MAX as u64) as _,
+            l_len: l_len.min(i64::MAX as u64) as _,
+            l_pid: self.pid,
+        }
+    }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// What `fcntl` wants done with a byte range lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockRequest {
+    /// `F_GETLK`, find a lock that would stop this one from being taken.
+    Test(Lock),
+    /// `F_SETLK`, take or release the lock, failing with `EAGAIN` if someone else is in the way.
+    Set(Lock),
+    /// `F_SETLKW`, take or release the lock, waiting for anyone in the way to get out of it.
+    Wait(Lock),
+}
+
+impl LockRequest {
+    pub fn lock(&self) -> &Lock {
+        match self {
+            Self::Test(lock) | Self::Set(lock) | Self::Wait(lock) => lock,
+        }
+    }
+
+    pub(crate) unsafe fn from_raw(cmd: c_int, lock: *const flock) -> Option<Self> {
+        let lock = Lock::from_flock(lock.as_ref()?)?;
+        match cmd {
+            libc::F_GETLK => Some(Self::Test(lock)),
+            libc::F_SETLK => Some(Self::Set(lock)),
+            libc::F_SETLKW => Some(Self::Wait(lock)),
+            _ => None,
+        }
+    }
+}
+
+// F_GETLK answers in place, with the lock in the way or F_UNLCK if there isn't one
+pub(crate) unsafe fn write_conflict(cmd: c_int, lock: *mut flock, conflict: Option<Lock>) -> c_int {
+    if cmd != libc::F_GETLK {
+        return 0;
+    }
+    if let Some(lock) = lock.as_mut() {
+        match conflict {
+            Some(conflict) => *lock = conflict.to_flock(),
+            None => lock.l_type = libc::F_UNLCK as _,
+        }
+    }
+    0
+}
+
+// flock(2)'s operation, as the kind of lock and whether to wait for it
+pub(crate) fn flock_op(op: c_int) -> Option<(LockKind, bool)> {
+    let kind = match op & !libc::LOCK_NB {
+        libc::LOCK_SH => LockKind::Read,
+        libc::LOCK_EX => LockKind::Write,
+        libc::LOCK_UN => LockKind::Unlock,
+        _ => return None,
+    };
+    Some((kind, op & libc::LOCK_NB == 0))
+}
+
+/// Keeps track of who holds which locks on which files, within this process.
+///
+/// Files are told apart by whatever `K` is, usually the path or the inode number. Owners
+/// are the `lock_owner` the kernel hands to `lock` and `flock`, one per process for
+/// byte range locks and one per open file for `flock`s, and the two kinds of lock
+/// don't get in each other's way, just like on Linux.
+///
+/// ```no_run
+/// # use fuse_sys::prelude::*;
+/// # use std::path::{Path, PathBuf};
+/// struct Fs {
+///     locks: LockManager<PathBuf>,
+/// }
+///
+/// #[filesystem]
+/// impl FileSystem for Fs {
+///     type FileHandle = ();
+///
+///     fn lock(&self, _req: &Request, path: &Path, _fh: Option<&()>, owner: u64, request: LockRequest) -> Result<Option<Lock>, Errno> {
+///         self.locks.lock(path.to_owned(), owner, request)
+///     }
+///
+///     fn flock(&self, _req: &Request, path: &Path, _fh: Option<&()>, owner: u64, kind: LockKind, wait: bool) -> Result<(), Errno> {
+///         self.locks.flock(path.to_owned(), owner, kind, wait)
+///     }
+/// }
+/// ```
+///
+/// libfuse unlocks everything an owner has on a file when it gets closed, through
+/// `lock` and `flock`, so there's nothing else to do with the high level api. With the
+/// low level one that's up to `flush` and `release`, which can call
+/// [`release`](Self::release).
+///
+/// A [`LockRequest::Wait`], or a `flock` that waits, blocks the thread handling it until
+/// the lock is free, and doesn't find out if the process asking for it gets interrupted in
+/// the meantime. A `Wait` for a lock whose owner is itself waiting on the one asking, directly
+/// or through others, fails with `EDEADLK` like it would with the kernel's own locks.
+///
+/// Whatever frees the lock has to come in as another request though, so waiting only works
+/// when there's a thread free to handle that. With an
+/// [`UnthreadedFileSystem`](crate::UnthreadedFileSystem) or a `LowLevelFileSystem`, which
+/// handle one request at a time, or once every one of
+/// [`ThreadingConfig::max_threads`](crate::ThreadingConfig::max_threads) is stuck waiting,
+/// the whole filesystem stops for good. Filesystems that can end up like that should hand
+/// over a [`LockRequest::Set`] and `wait: false` instead, so they get `EAGAIN`.
+#[derive(Debug)]
+pub struct LockManager<K> {
+    state: Mutex<State<K>>,
+    unlocked: Condvar,
+}
+
+#[derive(Debug)]
+struct State<K> {
+    files: HashMap<K, Held>,
+    // Who each owner stuck in a Wait is waiting on
+    waiting: HashMap<u64, u64>,
+}
+
+impl<K> State<K> {
+    // Whether waiting on `blocker` would end up waiting on `owner`
+    fn deadlocks(&self, owner: u64, blocker: u64) -> bool {
+        let mut next = blocker;
+        // Nobody waits on two owners at once, so the chain can't be longer than this
+        for _ in 0..=self.waiting.len() {
+            if next == owner {
+                return true;
+            }
+            match self.waiting.get(&next) {
+                Some(waiting_on) => next = *waiting_on,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug, Default)]
+struct Held {
+    ranges: Vec<(u64, Lock)>,
+    flocks: Vec<(u64, LockKind)>,
+}
+
+impl Held {
+    fn range_conflict(&self, owner: u64, lock: &Lock) -> Option<&(u64, Lock)> {
+        self.ranges
+            .iter()
+            .find(|(held_by, held)| *held_by != owner && held.kind.conflicts(lock.kind) && held.overlaps(lock))
+    }
+
+    fn flock_conflict(&self, owner: u64, kind: LockKind) -> bool {
+        self.flocks
+            .iter()
+            .any(|(held_by, held)| *held_by != owner && held.conflicts(kind))
+    }
+
+    // Replaces whatever the owner had over the lock's range, splitting anything that
+    // pokes out either side
+    fn set_range(&mut self, owner: u64, lock: Lock) {
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 2);
+        for (held_by, held) in self.ranges.drain(..) {
+            if held_by != owner || !held.overlaps(&lock) {
+                ranges.push((held_by, held));
+                continue;
+            }
+            if held.start < lock.start {
+                ranges.push((held_by, Lock { end: lock.start, ..held }));
+            }
+            if held.end > lock.end {
+                ranges.push((held_by, Lock { start: lock.end, ..held }));
+            }
+        }
+        if lock.kind != LockKind::Unlock {
+            ranges.push((owner, lock));
+        }
+        self.ranges = ranges;
+    }
+
+    fn set_flock(&mut self, owner: u64, kind: LockKind) {
+        self.flocks.retain(|(held_by, _)| *held_by != owner);
+        if kind != LockKind::Unlock {
+            self.flocks.push((owner, kind));
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.flocks.is_empty()
+    }
+}
+
+impl<K: Eq + Hash> LockManager<K> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                files: HashMap::new(),
+                waiting: HashMap::new(),
+            }),
+            unlocked: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State<K>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Handles a byte range lock the way `lock` should, returning the lock in the way
+    /// for a [`LockRequest::Test`].
+    pub fn lock(&self, file: K, owner: u64, request: LockRequest) -> Result<Option<Lock>, Errno> {
+        let lock = *request.lock();
+        if lock.start >= lock.end {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut state = self.state();
+        match request {
+            LockRequest::Test(_) if lock.kind == LockKind::Unlock => Err(Errno::EINVAL),
+            LockRequest::Test(_) => Ok(state
+                .files
+                .get(&file)
+                .and_then(|held| held.range_conflict(owner, &lock))
+                .map(|(_, held)| *held)),
+            LockRequest::Set(_) | LockRequest::Wait(_) => {
+                loop {
+                    let blocker = match state.files.get(&file).and_then(|held| held.range_conflict(owner, &lock)) {
+                        None => break,
+                        Some(_) if matches!(request, LockRequest::Set(_)) => return Err(Errno::EAGAIN),
+                        Some((held_by, _)) => *held_by,
+                    };
+                    if state.deadlocks(owner, blocker) {
+                        state.waiting.remove(&owner);
+                        return Err(Errno::EDEADLK);
+                    }
+
+                    state.waiting.insert(owner, blocker);
+                    state = self.unlocked.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                state.waiting.remove(&owner);
+
+                self.update(state, file, |held| held.set_range(owner, lock));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handles a whole file lock the way `flock` should.
+    pub fn flock(&self, file: K, owner: u64, kind: LockKind, wait: bool) -> Result<(), Errno> {
+        let mut state = self.state();
+        while state.files.get(&file).is_some_and(|held| held.flock_conflict(owner, kind)) {
+            if !wait {
+                return Err(Errno::EAGAIN);
+            }
+            state = self.unlocked.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+
+        self.update(state, file, |held| held.set_flock(owner, kind));
+        Ok(())
+    }
+
+    /// Drops every lock the owner holds on the file, of both kinds.
+    pub fn release(&self, file: &K, owner: u64) {
+        let mut state = self.state();
+        if let Some(held) = state.files.get_mut(file) {
+            held.ranges.retain(|(held_by, _)| *held_by != owner);
+            held.flocks.retain(|(held_by, _)| *held_by != owner);
+            if held.is_empty() {
+                state.files.remove(file);
+            }
+        }
+        self.unlocked.notify_all();
+    }
+
+    // Anything waiting gets woken up to check again, since there's no telling whose lock
+    // just got out of the way
+    fn update(&self, mut state: MutexGuard<'_, State<K>>, file: K, f: impl FnOnce(&mut Held)) {
+        match state.files.entry(file) {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+            Entry::Vacant(entry) => {
+                let mut held = Held::default();
+                f(&mut held);
+                if !held.is_empty() {
+                    entry.insert(held);
+                }
+            }
+        }
+        self.unlocked.notify_all();
+    }
+}
+
+impl<K: Eq + Hash> Default for LockManager<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+
+    fn lock(kind: LockKind, start: u64, end: u64) -> Lock {
+        Lock { kind, start, end, pid: 0 }
+    }
+
+    #[test]
+    fn from_flock() {
+        let mut raw = lock(LockKind::Write, 10, 15).to_flock();
+        assert_eq!(Lock::from_flock(&raw), Some(lock(LockKind::Write, 10, 15)));
+
+        // Negative lengths cover the bytes before the start
+        raw.l_len = -5;
+        assert_eq!(Lock::from_flock(&raw), Some(lock(LockKind::Write, 5, 10)));
+        raw.l_len = -20;
+        assert_eq!(Lock::from_flock(&raw), Some(lock(LockKind::Write, 0, 10)));
+
+        raw.l_len = 0;
+        assert_eq!(Lock::from_flock(&raw), Some(lock(LockKind::Write, 10, u64::MAX)));
+        assert_eq!(lock(LockKind::Read, 10, u64::MAX).to_flock().l_len, 0);
+
+        raw.l_type = 42;
+        assert_eq!(Lock::from_flock(&raw), None);
+    }
+
+    #[test]
+    fn conflicts() {
+        use LockKind::*;
+
+        assert!(!Read.conflicts(Read));
+        assert!(Read.conflicts(Write));
+        assert!(Write.conflicts(Read));
+        assert!(Write.conflicts(Write));
+        assert!(!Unlock.conflicts(Write) && !Write.conflicts(Unlock));
+
+        let locks = LockManager::new();
+        locks.lock("f", 1, LockRequest::Set(lock(Read, 0, 10))).unwrap();
+        locks.lock("f", 2, LockRequest::Set(lock(Read, 5, 15))).unwrap();
+        assert_eq!(locks.lock("f", 3, LockRequest::Set(lock(Write, 8, 9))), Err(Errno::EAGAIN));
+        assert_eq!(
+            locks.lock("f", 3, LockRequest::Test(lock(Write, 12, 20))),
+            Ok(Some(lock(Read, 5, 15)))
+        );
+        assert_eq!(locks.lock("f", 3, LockRequest::Test(lock(Write, 15, 20))), Ok(None));
+        // An owner's own locks never get in its way
+        assert_eq!(locks.lock("f", 1, LockRequest::Test(lock(Write, 0, 5))), Ok(None));
+    }
+
+    #[test]
+    fn splitting() {
+        let mut held = Held::default();
+        held.set_range(1, lock(LockKind::Read, 0, 100));
+        held.set_range(2, lock(LockKind::Read, 0, 100));
+
+        held.set_range(1, lock(LockKind::Write, 40, 60));
+        held.set_range(1, lock(LockKind::Unlock, 90, u64::MAX));
+
+        let mut ranges: Vec<_> = held.ranges.iter().filter(|(owner, _)| *owner == 1).map(|(_, lock)| *lock).collect();
+        ranges.sort_by_key(|lock| lock.start);
+        assert_eq!(
+            ranges,
+            [
+                lock(LockKind::Read, 0, 40),
+                lock(LockKind::Write, 40, 60),
+                lock(LockKind::Read, 60, 90),
+            ]
+        );
+        // Nobody else's locks get touched
+        assert!(held.ranges.contains(&(2, lock(LockKind::Read, 0, 100))));
+    }
+
+    #[test]
+    fn flock_and_fcntl_are_separate() {
+        let locks = LockManager::new();
+        locks.flock("f", 1, LockKind::Write, false).unwrap();
+        locks.lock("f", 2, LockRequest::Set(lock(LockKind::Write, 0, u64::MAX))).unwrap();
+
+        assert_eq!(locks.flock("f", 2, LockKind::Read, false), Err(Errno::EAGAIN));
+        assert_eq!(locks.lock("f", 1, LockRequest::Set(lock(LockKind::Read, 0, 1))), Err(Errno::EAGAIN));
+
+        locks.flock("f", 1, LockKind::Unlock, false).unwrap();
+        locks.flock("f", 2, LockKind::Read, false).unwrap();
+
+        locks.release(&"f", 2);
+        assert!(locks.state().files.is_empty());
+    }
+
+    #[test]
+    fn deadlocks() {
+        let locks = Arc::new(LockManager::new());
+        locks.lock("f", 1, LockRequest::Set(lock(LockKind::Write, 0, 10))).unwrap();
+        locks.lock("f", 2, LockRequest::Set(lock(LockKind::Write, 10, 20))).unwrap();
+
+        let waiter = thread::spawn({
+            let locks = locks.clone();
+            move || locks.lock("f", 1, LockRequest::Wait(lock(LockKind::Write, 10, 20)))
+        });
+        while !locks.state().waiting.contains_key(&1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(
+            locks.lock("f", 2, LockRequest::Wait(lock(LockKind::Write, 0, 10))),
+            Err(Errno::EDEADLK)
+        );
+
+        locks.release(&"f", 2);
+        assert_eq!(waiter.join().unwrap(), Ok(None));
+        assert!(locks.state().waiting.is_empty());
+    }
+}
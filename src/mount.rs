@@ -0,0 +1,456 @@
+//! Typed mount options, which get rendered into the argv libfuse parses.
+
+use std::{
+    error::Error,
+    ffi::{CString, OsString},
+    fmt,
+    io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::ThreadingConfig;
+
+/// Where and how to mount a filesystem. Made with [`MountOptions::builder`],
+/// which checks the options make sense together.
+///
+/// ```no_run
+/// # use fuse_sys::MountOptions;
+/// let options = MountOptions::builder("/tmp/fsmnt")
+///     .fsname("passthrough")
+///     .allow_other(true)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    mountpoint: PathBuf,
+    debug: bool,
+    allow_other: bool,
+    allow_root: bool,
+    read_only: bool,
+    default_permissions: bool,
+    auto_unmount: bool,
+    fsname: Option<String>,
+    subtype: Option<String>,
+    max_read: Option<u32>,
+    attr_timeout: Option<Duration>,
+    entry_timeout: Option<Duration>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    umask: Option<u32>,
+    custom: Vec<String>,
+    threading: ThreadingConfig,
+}
+
+impl MountOptions {
+    pub fn builder(mountpoint: impl Into<PathBuf>) -> MountOptionsBuilder {
+        MountOptionsBuilder(Self {
+            mountpoint: mountpoint.into(),
+            debug: false,
+            allow_other: false,
+            allow_root: false,
+            read_only: false,
+            default_permissions: false,
+            auto_unmount: false,
+            fsname: None,
+            subtype: None,
+            max_read: None,
+            attr_timeout: None,
+            entry_timeout: None,
+            uid: None,
+            gid: None,
+            umask: None,
+            custom: vec![],
+            threading: ThreadingConfig::new(),
+        })
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    pub(crate) fn threading(&self) -> &ThreadingConfig {
+        &self.threading
+    }
+
+    // Everything that goes in a -o, minus the options the high level library handles itself
+    fn options(&self, high_level: bool) -> Vec<String> {
+        let flags = [
+            (self.allow_other, "allow_other"),
+            (self.allow_root, "allow_root"),
+            (self.read_only, "ro"),
+            (self.default_permissions, "default_permissions"),
+            (self.auto_unmount, "auto_unmount"),
+        ];
+
+        let mut options: Vec<String> = flags
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| flag.to_owned())
+            .collect();
+
+        if let Some(fsname) = &self.fsname {
+            options.push(format!("fsname={fsname}"));
+        }
+        if let Some(subtype) = &self.subtype {
+            options.push(format!("subtype={subtype}"));
+        }
+        if let Some(max_read) = self.max_read {
+            options.push(format!("max_read={max_read}"));
+        }
+
+        if high_level {
+            if let Some(timeout) = self.attr_timeout {
+                options.push(format!("attr_timeout={}", timeout.as_secs_f64()));
+            }
+            if let Some(timeout) = self.entry_timeout {
+                options.push(format!("entry_timeout={}", timeout.as_secs_f64()));
+            }
+            if let Some(uid) = self.uid {
+                options.push(format!("uid={uid}"));
+            }
+            if let Some(gid) = self.gid {
+                options.push(format!("gid={gid}"));
+            }
+            if let Some(umask) = self.umask {
+                options.push(format!("umask={umask:o}"));
+            }
+        }
+
+        options.extend(self.custom.iter().cloned());
+        options
+    }
+
+    /// The arguments libfuse gets, program name first. The filesystem always
+    /// stays in the foreground, since forking would leave any other threads behind.
+    #[doc(hidden)]
+    pub fn to_args(&self, high_level: bool) -> Vec<CString> {
+        let program = std::env::args_os()
+            .next()
+            .unwrap_or_else(|| OsString::from("fuse"));
+
+        // build already made sure none of these have nuls in them
+        let mut args = vec![
+            CString::new(program.into_vec()).unwrap_or_default(),
+            CString::new(self.mountpoint.as_os_str().as_bytes()).unwrap(),
+            CString::new("-f").unwrap(),
+        ];
+        if self.debug {
+            args.push(CString::new("-d").unwrap());
+        }
+
+        let options = self.options(high_level);
+        if !options.is_empty() {
+            args.push(CString::new("-o").unwrap());
+            args.push(CString::new(options.join(",")).unwrap());
+        }
+
+        args
+    }
+}
+
+/// Sets up a [`MountOptions`]. Everything is off unless it's set.
+#[derive(Clone, Debug)]
+pub struct MountOptionsBuilder(MountOptions);
+
+impl MountOptionsBuilder {
+    /// Prints every request and reply to stderr.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.0.debug = debug;
+        self
+    }
+
+    /// Lets users other than the one mounting access the filesystem.
+    /// Needs `user_allow_other` in /etc/fuse.conf unless mounting as root.
+    pub fn allow_other(mut self, allow: bool) -> Self {
+        self.0.allow_other = allow;
+        self
+    }
+
+    /// Lets root access the filesystem along with the user mounting it.
+    pub fn allow_root(mut self, allow: bool) -> Self {
+        self.0.allow_root = allow;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.0.read_only = read_only;
+        self
+    }
+
+    /// Has the kernel check permissions against the file modes, instead of leaving it to `access`.
+    pub fn default_permissions(mut self, check: bool) -> Self {
+        self.0.default_permissions = check;
+        self
+    }
+
+    /// Unmounts the filesystem if the process exits without unmounting it.
+    pub fn auto_unmount(mut self, auto: bool) -> Self {
+        self.0.auto_unmount = auto;
+        self
+    }
+
+    /// What shows up as the source in /proc/mounts.
+    pub fn fsname(mut self, fsname: impl Into<String>) -> Self {
+        self.0.fsname = Some(fsname.into());
+        self
+    }
+
+    /// What shows up after `fuse.` as the type in /proc/mounts.
+    pub fn subtype(mut self, subtype: impl Into<String>) -> Self {
+        self.0.subtype = Some(subtype.into());
+        self
+    }
+
+    /// The most the kernel asks for in one read.
+    pub fn max_read(mut self, bytes: u32) -> Self {
+        self.0.max_read = Some(bytes);
+        self
+    }
+
+    /// How long the kernel may cache attributes. Only the high level api uses this,
+    /// the low level api says so in every reply instead.
+    pub fn attr_timeout(mut self, timeout: Duration) -> Self {
+        self.0.attr_timeout = Some(timeout);
+        self
+    }
+
+    /// How long the kernel may cache names. Only the high level api uses this,
+    /// the low level api says so in every reply instead.
+    pub fn entry_timeout(mut self, timeout: Duration) -> Self {
+        self.0.entry_timeout = Some(timeout);
+        self
+    }
+
+    /// Makes every file look like it's owned by `uid`, whatever `getattr` says.
+    /// High level api only.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.0.uid = Some(uid);
+        self
+    }
+
+    /// Makes every file look like it's owned by group `gid`, whatever `getattr` says.
+    /// High level api only.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.0.gid = Some(gid);
+        self
+    }
+
+    /// Masks the permissions of every file `getattr` returns. High level api only.
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.0.umask = Some(umask);
+        self
+    }
+
+    /// Any other `-o` option, like `use_ino` or `kernel_cache`, which gets passed along untouched.
+    pub fn option(mut self, option: impl Into<String>) -> Self {
+        self.0.custom.push(option.into());
+        self
+    }
+
+    /// How a threaded [`FileSystem`](crate::FileSystem) gets spread over threads. Everything
+    /// else is served from one thread, which still runs the config's `on_thread_start`.
+    pub fn threading(mut self, threading: ThreadingConfig) -> Self {
+        self.0.threading = threading;
+        self
+    }
+
+    pub fn build(self) -> Result<MountOptions, MountOptionsError> {
+        let options = self.0;
+
+        if options.allow_other && options.allow_root {
+            return Err(MountOptionsError::Conflict("allow_other", "allow_root"));
+        }
+
+        if options.mountpoint.as_os_str().is_empty() {
+            return Err(invalid("mountpoint", ""));
+        }
+        if options.mountpoint.as_os_str().as_bytes().contains(&0) {
+            return Err(invalid(
+                "mountpoint",
+                &options.mountpoint.to_string_lossy(),
+            ));
+        }
+
+        // libfuse splits -o on commas, so they can't show up in a value
+        let strings = [("fsname", &options.fsname), ("subtype", &options.subtype)];
+        for (option, value) in strings {
+            match value {
+                Some(value) if value.is_empty() || value.contains([',', '\0']) => {
+                    return Err(invalid(option, value))
+                }
+                _ => {}
+            }
+        }
+        for option in &options.custom {
+            if option.is_empty() || option.contains([',', '\0']) {
+                return Err(invalid("option", option));
+            }
+        }
+
+        let threading = &options.threading;
+        if threading.clone_fd && cfg!(not(feature = "fuse3")) {
+            return Err(invalid("clone_fd", "true"));
+        }
+        if let Some(setting) = threading.ignored_by_clone_fd().filter(|_| threading.clone_fd) {
+            return Err(MountOptionsError::Conflict("clone_fd", setting));
+        }
+
+        if options.threading.max_threads == 0 {
+            return Err(invalid("max_threads", "0"));
+        }
+        if options.threading.thread_name_prefix.contains('\0') {
+            return Err(invalid("thread_name_prefix", &options.threading.thread_name_prefix));
+        }
+
+        if options.max_read == Some(0) {
+            return Err(invalid("max_read", "0"));
+        }
+        if let Some(umask) = options.umask.filter(|umask| *umask > 0o777) {
+            return Err(invalid("umask", &format!("{umask:o}")));
+        }
+
+        Ok(options)
+    }
+}
+
+fn invalid(option: &'static str, value: &str) -> MountOptionsError {
+    MountOptionsError::Invalid {
+        option,
+        value: value.to_owned(),
+    }
+}
+
+/// Why [`MountOptionsBuilder::build`] turned the options down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountOptionsError {
+    /// Two options that can't be used together were both set.
+    Conflict(&'static str, &'static str),
+    /// An option was given a value that libfuse can't take.
+    Invalid { option: &'static str, value: String },
+}
+
+impl fmt::Display for MountOptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountOptionsError::Conflict(a, b) => write!(f, "{a} and {b} can't be used together"),
+            MountOptionsError::Invalid { option, value } => {
+                write!(f, "invalid value for {option}: {value:?}")
+            }
+        }
+    }
+}
+
+impl Error for MountOptionsError {}
+
+/// Why a filesystem couldn't be mounted, or stopped being served.
+#[derive(Debug)]
+pub enum MountError {
+    /// The filesystem never got mounted.
+    Mount(io::Error),
+    /// The loop serving requests failed, with whatever libfuse returned.
+    Session(i32),
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountError::Mount(e) => write!(f, "couldn't mount the filesystem: {e}"),
+            MountError::Session(code) => write!(f, "serving the filesystem failed with {code}"),
+        }
+    }
+}
+
+impl Error for MountError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MountError::Mount(e) => Some(e),
+            MountError::Session(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(f: impl FnOnce(MountOptionsBuilder) -> MountOptionsBuilder) -> Result<MountOptions, MountOptionsError> {
+        f(MountOptions::builder("/tmp/mnt")).build()
+    }
+
+    #[test]
+    fn defaults() {
+        let options = build(|b| b).unwrap();
+        let args: Vec<_> = options.to_args(true).into_iter().skip(1).collect();
+        assert_eq!(args, [CString::new("/tmp/mnt").unwrap(), CString::new("-f").unwrap()]);
+    }
+
+    #[test]
+    fn conflicts() {
+        assert_eq!(
+            build(|b| b.allow_other(true).allow_root(true)).unwrap_err(),
+            MountOptionsError::Conflict("allow_other", "allow_root")
+        );
+        assert!(build(|b| b.allow_other(true).allow_root(false)).is_ok());
+    }
+
+    #[cfg(feature = "fuse3")]
+    #[test]
+    fn clone_fd() {
+        assert!(build(|b| b.threading(ThreadingConfig::new().clone_fd(true))).is_ok());
+        assert_eq!(
+            build(|b| b.threading(ThreadingConfig::new().clone_fd(true).max_threads(4))).unwrap_err(),
+            MountOptionsError::Conflict("clone_fd", "max_threads")
+        );
+        assert_eq!(
+            build(|b| b.threading(ThreadingConfig::new().clone_fd(true).on_thread_start(|| ()))).unwrap_err(),
+            MountOptionsError::Conflict("clone_fd", "on_thread_start")
+        );
+    }
+
+    #[cfg(not(feature = "fuse3"))]
+    #[test]
+    fn clone_fd() {
+        assert_eq!(
+            build(|b| b.threading(ThreadingConfig::new().clone_fd(true))).unwrap_err(),
+            invalid("clone_fd", "true")
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(MountOptions::builder("").build().unwrap_err(), invalid("mountpoint", ""));
+        assert!(MountOptions::builder("/tmp/m\0nt").build().is_err());
+        assert_eq!(build(|b| b.fsname("a,b")).unwrap_err(), invalid("fsname", "a,b"));
+        assert_eq!(build(|b| b.subtype("")).unwrap_err(), invalid("subtype", ""));
+        assert_eq!(build(|b| b.option("ro,exec")).unwrap_err(), invalid("option", "ro,exec"));
+        assert_eq!(build(|b| b.max_read(0)).unwrap_err(), invalid("max_read", "0"));
+        assert_eq!(build(|b| b.umask(0o1777)).unwrap_err(), invalid("umask", "1777"));
+        assert_eq!(
+            build(|b| b.threading(ThreadingConfig::new().max_threads(0))).unwrap_err(),
+            invalid("max_threads", "0")
+        );
+        assert!(build(|b| b.threading(ThreadingConfig::new().thread_name_prefix("a\0b"))).is_err());
+    }
+
+    #[test]
+    fn high_level_only_options() {
+        let options = build(|b| {
+            b.read_only(true)
+                .fsname("test")
+                .uid(1000)
+                .umask(0o22)
+                .attr_timeout(Duration::from_millis(500))
+                .option("kernel_cache")
+        })
+        .unwrap();
+
+        assert_eq!(
+            options.options(true),
+            ["ro", "fsname=test", "attr_timeout=0.5", "uid=1000", "umask=22", "kernel_cache"]
+        );
+        assert_eq!(options.options(false), ["ro", "fsname=test", "kernel_cache"]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Waking up `poll`, `select` and `epoll` once a file's ready.
+
+use std::{
+    fmt, io,
+    ptr::NonNull,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+};
+
+use crate::fuse_pollhandle;
+
+/// What `poll` gets handed when someone's waiting on the file. Hang onto it and
+/// [`notify`](Self::notify) once the file's ready, and the kernel asks again.
+///
+/// Every time the kernel asks it hands over a new one, and any of them wake up
+/// everyone waiting on the file, so keeping the latest is enough. Dropping it
+/// without notifying just means nobody gets woken up through it.
+///
+/// It can outlive the session it came from, once the filesystem's been unmounted and
+/// handed back, but then there's nobody left to notify and it fails with `ENODEV`.
+///
+/// ```no_run
+/// # use fuse_sys::prelude::*;
+/// # use std::{path::Path, sync::Mutex};
+/// struct Log {
+///     waiting: Mutex<Option<PollHandle>>,
+/// }
+///
+/// impl Log {
+///     // Called from wherever new lines come from
+///     fn appended(&self) {
+///         if let Some(handle) = self.waiting.lock().unwrap().take() {
+///             let _ = handle.notify();
+///         }
+///     }
+/// }
+///
+/// #[filesystem]
+/// impl FileSystem for Log {
+///     type FileHandle = ();
+///
+///     fn poll(&self, _req: &Request, _path: &Path, _fh: Option<&()>, handle: Option<PollHandle>) -> Result<u32, Errno> {
+///         if let Some(handle) = handle {
+///             *self.waiting.lock().unwrap() = Some(handle);
+///         }
+///         Ok(libc::POLLOUT as u32)
+///     }
+/// }
+/// ```
+pub struct PollHandle {
+    ph: NonNull<fuse_pollhandle>,
+    session: Liveness,
+}
+
+// libfuse's notifications can be sent from any thread
+unsafe impl Send for PollHandle {}
+unsafe impl Sync for PollHandle {}
+
+impl PollHandle {
+    pub(crate) unsafe fn from_raw(ph: *mut fuse_pollhandle, session: &Liveness) -> Option<Self> {
+        NonNull::new(ph).map(|ph| Self {
+            ph,
+            session: session.clone(),
+        })
+    }
+
+    /// Tells the kernel the file's ready, so it polls it again.
+    pub fn notify(&self) -> io::Result<()> {
+        // Held onto so the session can't go away halfway through
+        let alive = self.session.alive();
+        if !*alive {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV));
+        }
+
+        #[cfg(not(feature = "pure_rust"))]
+        match unsafe { crate::fuse_lowlevel_notify_poll(self.ph.as_ptr()) } {
+            0 => Ok(()),
+            err => Err(io::Error::from_raw_os_error(-err)),
+        }
+
+        // Nothing gets polled without libfuse
+        #[cfg(feature = "pure_rust")]
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+impl Drop for PollHandle {
+    // Once the session's gone the handle gets leaked, rather than handing libfuse back
+    // something that belonged to a session it's already torn down
+    fn drop(&mut self) {
+        #[cfg(not(feature = "pure_rust"))]
+        if *self.session.alive() {
+            unsafe { crate::fuse_pollhandle_destroy(self.ph.as_ptr()) };
+        }
+    }
+}
+
+impl fmt::Debug for PollHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PollHandle").field(&self.ph).finish()
+    }
+}
+
+/// Whether the session poll handles came from is still running. Sessions [`end`](Self::end)
+/// it before they're torn down, which waits for any handle that's in the middle of using it.
+#[derive(Clone, Debug)]
+pub(crate) struct Liveness(Arc<RwLock<bool>>);
+
+impl Liveness {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(RwLock::new(true)))
+    }
+
+    pub(crate) fn end(&self) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = false;
+    }
+
+    fn alive(&self) -> RwLockReadGuard<'_, bool> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // The low level api only runs one session at a time, which keeps its liveness here
+    #[cfg(not(feature = "pure_rust"))]
+    pub(crate) fn current(running: &std::sync::Mutex<Option<Liveness>>) -> Self {
+        running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .unwrap_or_else(|| {
+                let ended = Self::new();
+                ended.end();
+                ended
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outlives_session() {
+        let session = Liveness::new();
+        // Never used for anything, since the session's over before it gets notified
+        let handle = unsafe { PollHandle::from_raw(NonNull::dangling().as_ptr(), &session) }.unwrap();
+
+        session.end();
+        assert_eq!(handle.notify().unwrap_err().raw_os_error(), Some(libc::ENODEV));
+        drop(handle);
+    }
+}
@@ -0,0 +1,422 @@
+//! Typed wrappers around the `fuse_reply_*` functions used by the low level API.
+//!
+//! Every low level request has to be answered exactly once. Each reply type
+//! consumes itself when it answers, and answers with `EIO` if it gets dropped
+//! without being used, so forgetting to reply can't hang the caller.
+
+use std::{
+    ffi::{CString, OsStr},
+    os::{
+        raw::{c_char, c_int},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    flock, fuse_add_direntry, fuse_entry_param, fuse_file_info, fuse_reply_attr, fuse_reply_bmap,
+    fuse_reply_buf, fuse_reply_create, fuse_reply_entry, fuse_reply_err, fuse_reply_ioctl,
+    fuse_reply_lock, fuse_reply_none, fuse_reply_open, fuse_reply_poll, fuse_reply_readlink,
+    fuse_reply_statfs, fuse_reply_write, fuse_reply_xattr, fuse_req_t, mode_t, off_t, stat,
+    statvfs, Errno, Lock,
+};
+
+/// Behavior shared by every reply type.
+pub trait Reply {
+    /// Fails the request with `errno`.
+    fn error(self, errno: Errno);
+}
+
+struct ReplyRaw {
+    req: fuse_req_t,
+}
+
+// libfuse doesn't care which thread a request gets answered from
+unsafe impl Send for ReplyRaw {}
+
+impl ReplyRaw {
+    fn send(self, reply: impl FnOnce(fuse_req_t) -> c_int) {
+        let req = self.req;
+        std::mem::forget(self);
+        reply(req);
+    }
+}
+
+impl Drop for ReplyRaw {
+    fn drop(&mut self) {
+        unsafe { fuse_reply_err(self.req, Errno::EIO.raw()) };
+    }
+}
+
+fn entry_param(ttl: Duration, attr: &stat, generation: u64) -> fuse_entry_param {
+    fuse_entry_param {
+        ino: attr.st_ino,
+        generation: generation as _,
+        attr: *attr,
+        attr_timeout: ttl.as_secs_f64(),
+        entry_timeout: ttl.as_secs_f64(),
+    }
+}
+
+macro_rules! reply_types {
+    ($($(#[$meta:meta])* $name:ident),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            pub struct $name {
+                raw: ReplyRaw,
+            }
+
+            impl $name {
+                pub(crate) unsafe fn new(req: fuse_req_t) -> Self {
+                    Self { raw: ReplyRaw { req } }
+                }
+            }
+
+            impl Reply for $name {
+                fn error(self, errno: Errno) {
+                    self.raw.send(|req| unsafe { fuse_reply_err(req, errno.raw()) });
+                }
+            }
+        )*
+    };
+}
+
+reply_types! {
+    /// Answers requests that only succeed or fail, like `unlink` or `flush`.
+    ReplyEmpty,
+    /// Answers `lookup`, `mknod`, `mkdir`, `symlink` and `link`.
+    ReplyEntry,
+    /// Answers `getattr` and `setattr`.
+    ReplyAttr,
+    /// Answers `readlink`.
+    ReplyReadlink,
+    /// Answers `open` and `opendir`.
+    ReplyOpen,
+    /// Answers `read`.
+    ReplyData,
+    /// Answers `write` and `write_buf`.
+    ReplyWrite,
+    /// Answers `statfs`.
+    ReplyStatfs,
+    /// Answers `getxattr` and `listxattr`.
+    ReplyXattr,
+    /// Answers `create`.
+    ReplyCreate,
+    /// Answers `getlk`.
+    ReplyLock,
+    /// Answers `bmap`.
+    ReplyBmap,
+    /// Answers `ioctl`.
+    ReplyIoctl,
+    /// Answers `poll`.
+    ReplyPoll,
+}
+
+impl ReplyEmpty {
+    pub fn ok(self) {
+        self.raw.send(|req| unsafe { fuse_reply_err(req, 0) });
+    }
+}
+
+impl ReplyEntry {
+    /// `ttl` is how long the kernel may cache both the name and the attributes.
+    pub fn entry(self, ttl: Duration, attr: &stat, generation: u64) {
+        let entry = entry_param(ttl, attr, generation);
+        self.raw
+            .send(|req| unsafe { fuse_reply_entry(req, &entry) });
+    }
+}
+
+impl ReplyAttr {
+    pub fn attr(self, ttl: Duration, attr: &stat) {
+        self.raw
+            .send(|req| unsafe { fuse_reply_attr(req, attr, ttl.as_secs_f64()) });
+    }
+}
+
+impl ReplyReadlink {
+    /// Answers with `EINVAL` if `link` has a nul in it.
+    pub fn link(self, link: &Path) {
+        let link = match CString::new(link.as_os_str().as_bytes()) {
+            Ok(link) => link,
+            Err(_) => return self.error(Errno::EINVAL),
+        };
+        self.raw
+            .send(|req| unsafe { fuse_reply_readlink(req, link.as_ptr()) });
+    }
+}
+
+impl ReplyOpen {
+    /// Replies with the `fh` and flags set on `info`.
+    pub fn opened(self, info: &fuse_file_info) {
+        self.raw.send(|req| unsafe { fuse_reply_open(req, info) });
+    }
+}
+
+impl ReplyData {
+    pub fn data(self, data: &[u8]) {
+        self.raw.send(|req| unsafe {
+            fuse_reply_buf(req, data.as_ptr() as *const c_char, data.len() as _)
+        });
+    }
+}
+
+impl ReplyWrite {
+    pub fn written(self, count: usize) {
+        self.raw
+            .send(|req| unsafe { fuse_reply_write(req, count as _) });
+    }
+}
+
+impl ReplyStatfs {
+    pub fn statfs(self, stat: &statvfs) {
+        self.raw.send(|req| unsafe { fuse_reply_statfs(req, stat) });
+    }
+}
+
+impl ReplyXattr {
+    /// Answers a size probe (a request with a size of 0) with the size the value needs.
+    pub fn size(self, size: usize) {
+        self.raw
+            .send(|req| unsafe { fuse_reply_xattr(req, size as _) });
+    }
+
+    pub fn data(self, data: &[u8]) {
+        self.raw.send(|req| unsafe {
+            fuse_reply_buf(req, data.as_ptr() as *const c_char, data.len() as _)
+        });
+    }
+}
+
+impl ReplyCreate {
+    pub fn created(self, ttl: Duration, attr: &stat, generation: u64, info: &fuse_file_info) {
+        let entry = entry_param(ttl, attr, generation);
+        self.raw
+            .send(|req| unsafe { fuse_reply_create(req, &entry, info) });
+    }
+}
+
+impl ReplyLock {
+    pub fn locked(self, lock: &flock) {
+        self.raw.send(|req| unsafe { fuse_reply_lock(req, lock) });
+    }
+
+    /// The lock that's in the way, or `None` if there isn't one.
+    pub fn conflict(self, lock: Option<&Lock>) {
+        let lock = match lock {
+            Some(lock) => lock.to_flock(),
+            None => flock {
+                l_type: libc::F_UNLCK as _,
+                ..unsafe { std::mem::zeroed() }
+            },
+        };
+        self.locked(&lock);
+    }
+}
+
+impl ReplyBmap {
+    pub fn bmap(self, idx: u64) {
+        self.raw.send(|req| unsafe { fuse_reply_bmap(req, idx) });
+    }
+}
+
+impl ReplyIoctl {
+    pub fn ioctl(self, result: c_int, data: &[u8]) {
+        self.raw.send(|req| unsafe {
+            fuse_reply_ioctl(req, result, data.as_ptr() as *const _, data.len() as _)
+        });
+    }
+}
+
+impl ReplyPoll {
+    pub fn poll(self, revents: u32) {
+        self.raw
+            .send(|req| unsafe { fuse_reply_poll(req, revents) });
+    }
+}
+
+/// Answers `forget`, which the kernel never waits on. Those can't be answered with an
+/// error, so unlike the other replies dropping this one is the same as using it.
+pub struct ReplyNone {
+    req: fuse_req_t,
+}
+
+unsafe impl Send for ReplyNone {}
+
+impl ReplyNone {
+    pub(crate) unsafe fn new(req: fuse_req_t) -> Self {
+        Self { req }
+    }
+
+    pub fn none(self) {
+        // Dropping it is what answers it
+    }
+}
+
+impl Drop for ReplyNone {
+    fn drop(&mut self) {
+        unsafe { fuse_reply_none(self.req) };
+    }
+}
+
+impl Reply for ReplyNone {
+    /// There's nobody to tell about the error, so this is the same as [`ReplyNone::none`].
+    fn error(self, _errno: Errno) {
+        self.none()
+    }
+}
+
+/// Answers `readdir` by packing as many entries as fit into the size the kernel asked for.
+pub struct ReplyDirectory {
+    raw: ReplyRaw,
+    buf: Vec<u8>,
+    // What the kernel asked for, which the Vec's capacity might be more than
+    size: usize,
+}
+
+impl ReplyDirectory {
+    pub(crate) unsafe fn new(req: fuse_req_t, size: usize) -> Self {
+        Self {
+            raw: ReplyRaw { req },
+            buf: Vec::with_capacity(size),
+            size,
+        }
+    }
+
+    /// Adds an entry to the reply. `offset` is the offset the kernel should pass back
+    /// to readdir to continue listing after this entry.
+    ///
+    /// Returns true if the buffer is full, in which case the entry wasn't added.
+    /// Names with a nul in them can't be file names, so they're skipped.
+    pub fn add(&mut self, ino: u64, offset: off_t, mode: mode_t, name: &OsStr) -> bool {
+        let name = match CString::new(name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        let stat = stat {
+            st_ino: ino,
+            st_mode: mode,
+            ..Default::default()
+        };
+
+        let len = self.buf.len();
+        let remaining = self.size - len;
+        let needed = unsafe {
+            fuse_add_direntry(
+                self.raw.req,
+                self.buf.as_mut_ptr().add(len) as *mut c_char,
+                remaining as _,
+                name.as_ptr(),
+                &stat,
+                offset,
+            )
+        };
+
+        if needed > remaining {
+            return true;
+        }
+
+        unsafe { self.buf.set_len(len + needed) };
+        false
+    }
+
+    pub fn ok(self) {
+        let Self { raw, buf, .. } = self;
+        raw.send(|req| unsafe {
+            fuse_reply_buf(req, buf.as_ptr() as *const c_char, buf.len() as _)
+        });
+    }
+}
+
+impl Reply for ReplyDirectory {
+    fn error(self, errno: Errno) {
+        self.raw
+            .send(|req| unsafe { fuse_reply_err(req, errno.raw()) });
+    }
+}
+
+/// Answers `readdirplus`, which hands the kernel full entries instead of just names.
+#[cfg(feature = "fuse3")]
+pub struct ReplyDirectoryPlus {
+    raw: ReplyRaw,
+    buf: Vec<u8>,
+    // What the kernel asked for, which the Vec's capacity might be more than
+    size: usize,
+}
+
+#[cfg(feature = "fuse3")]
+impl ReplyDirectoryPlus {
+    pub(crate) unsafe fn new(req: fuse_req_t, size: usize) -> Self {
+        Self {
+            raw: ReplyRaw { req },
+            buf: Vec::with_capacity(size),
+            size,
+        }
+    }
+
+    /// Same as [`ReplyDirectory::add`], except the entry also gets looked up.
+    pub fn add(
+        &mut self,
+        ttl: Duration,
+        attr: &stat,
+        generation: u64,
+        offset: off_t,
+        name: &OsStr,
+    ) -> bool {
+        let name = match CString::new(name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        let entry = entry_param(ttl, attr, generation);
+
+        let len = self.buf.len();
+        let remaining = self.size - len;
+        let needed = unsafe {
+            crate::fuse_add_direntry_plus(
+                self.raw.req,
+                self.buf.as_mut_ptr().add(len) as *mut c_char,
+                remaining as _,
+                name.as_ptr(),
+                &entry,
+                offset,
+            )
+        };
+
+        if needed > remaining {
+            return true;
+        }
+
+        unsafe { self.buf.set_len(len + needed) };
+        false
+    }
+
+    pub fn ok(self) {
+        let Self { raw, buf, .. } = self;
+        raw.send(|req| unsafe {
+            fuse_reply_buf(req, buf.as_ptr() as *const c_char, buf.len() as _)
+        });
+    }
+}
+
+#[cfg(feature = "fuse3")]
+impl Reply for ReplyDirectoryPlus {
+    fn error(self, errno: Errno) {
+        self.raw
+            .send(|req| unsafe { fuse_reply_err(req, errno.raw()) });
+    }
+}
+
+#[cfg(feature = "fuse3")]
+reply_types! {
+    /// Answers `lseek`.
+    ReplyLseek,
+}
+
+#[cfg(feature = "fuse3")]
+impl ReplyLseek {
+    pub fn offset(self, offset: off_t) {
+        self.raw
+            .send(|req| unsafe { crate::fuse_reply_lseek(req, offset) });
+    }
+}
@@ -0,0 +1,65 @@
+use crate::fuse_context;
+
+/// Who's making the request being handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    umask: u32,
+}
+
+impl Request {
+    pub(crate) unsafe fn from_context(context: *const fuse_context) -> Self {
+        match context.as_ref() {
+            Some(context) => Self {
+                uid: context.uid as _,
+                gid: context.gid as _,
+                pid: context.pid as _,
+                umask: context.umask as _,
+            },
+            None => Self {
+                uid: 0,
+                gid: 0,
+                pid: 0,
+                umask: 0,
+            },
+        }
+    }
+
+    /// The low level api keeps the same thing on the request instead of in a thread local.
+    #[cfg(not(feature = "pure_rust"))]
+    pub(crate) unsafe fn from_req(req: crate::fuse_req_t) -> Self {
+        match crate::fuse_req_ctx(req).as_ref() {
+            Some(ctx) => Self {
+                uid: ctx.uid as _,
+                gid: ctx.gid as _,
+                pid: ctx.pid as _,
+                umask: ctx.umask as _,
+            },
+            None => Self::from_context(std::ptr::null()),
+        }
+    }
+
+    /// The user id of the calling process.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// The group id of the calling process.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// The thread id of the calling process, or 0 if the kernel didn't say.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// The umask of the calling process. This is only filled in for
+    /// `mknod`, `mkdir` and `create`, and only when the kernel leaves applying it to us.
+    /// Otherwise the mode those get has already been masked.
+    pub fn umask(&self) -> u32 {
+        self.umask
+    }
+}
@@ -0,0 +1,608 @@
+//! Mounting and serving high level filesystems, either on the thread that mounted them
+//! or on one of their own, so the process can carry on and unmount them whenever it likes.
+
+use std::{
+    ffi::{c_void, CString},
+    io,
+    os::raw::c_int,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{fuse_operations, fusermount, poll::Liveness, FuseMain, MountError, MountOptions, ThreadingConfig, UserData};
+
+/// A filesystem mounted by [`spawn_mount`], being served on another thread.
+///
+/// Dropping it unmounts the filesystem and waits for the thread to finish.
+pub struct BackgroundSession<F> {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<F, MountError>>>,
+}
+
+// What FuseMain::run does. Like fuse_main, SIGINT, SIGTERM and SIGHUP unmount the filesystem.
+pub(crate) fn run<const UNTHREADED: bool, F: FuseMain<UNTHREADED>>(
+    fs: F,
+    options: &MountOptions,
+) -> Result<F, MountError> {
+    let (mount, owned) = mount(fs, options).map_err(MountError::Mount)?;
+
+    let out = unsafe { mount.serve(!UNTHREADED, options.threading(), true, || owned.session.end()) };
+    let fs = unsafe { owned.into_inner() };
+
+    match out {
+        0 => Ok(fs),
+        e => Err(MountError::Session(e)),
+    }
+}
+
+/// Mounts `fs` and serves it on a new thread until the returned session
+/// is unmounted or dropped, or someone else unmounts it.
+pub fn spawn_mount<const UNTHREADED: bool, F>(
+    fs: F,
+    options: &MountOptions,
+) -> io::Result<BackgroundSession<F>>
+where
+    F: FuseMain<UNTHREADED> + Send,
+{
+    let (mount, owned) = mount(fs, options)?;
+
+    let shared = Arc::new(Shared {
+        mountpoint: options.mountpoint().to_owned(),
+        connection: connection(options.mountpoint()),
+        exit: Mutex::new(Some(mount.exit())),
+        finished: Mutex::new(false),
+        finishing: Condvar::new(),
+    });
+
+    // The mount only gets handed over once the thread's running, so that it can still
+    // be torn down here if the thread can't be started
+    let (handover, takeover) = mpsc::sync_channel::<(Mount, Owned<F>)>(1);
+
+    let threading = options.threading().clone();
+    let thread_shared = shared.clone();
+    let thread = thread::Builder::new()
+        .name("fuse".to_owned())
+        .spawn(move || {
+            let shared = thread_shared;
+            let (mount, owned) = takeover.recv().expect("mount never handed over");
+
+            // Once the loop's stopped there's nothing left to tell to exit
+            let out = unsafe {
+                mount.serve(!UNTHREADED, &threading, false, || {
+                    owned.session.end();
+                    shared.stopped()
+                })
+            };
+            let fs = unsafe { owned.into_inner() };
+            shared.finish();
+
+            match out {
+                0 => Ok(fs),
+                e => Err(MountError::Session(e)),
+            }
+        });
+
+    let thread = match thread {
+        Ok(thread) => thread,
+        Err(e) => {
+            unsafe {
+                mount.close();
+                drop(owned.into_inner());
+            }
+            return Err(e);
+        }
+    };
+    let _ = handover.send((mount, owned));
+
+    Ok(BackgroundSession {
+        shared,
+        thread: Some(thread),
+    })
+}
+
+impl<F> BackgroundSession<F> {
+    pub fn mountpoint(&self) -> &Path {
+        &self.shared.mountpoint
+    }
+
+    /// Unmounts the filesystem, which stops the loop. Requests that are
+    /// already being handled get to finish.
+    pub fn unmount(&self) -> io::Result<()> {
+        fusermount::unmount(self.shared.mountpoint.as_os_str())
+    }
+
+    /// Something other threads can use to shut the filesystem down.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Whether the loop has stopped and the filesystem has been destroyed.
+    pub fn is_finished(&self) -> bool {
+        self.shared.is_finished()
+    }
+
+    /// Waits for the loop to stop and hands the filesystem back. This doesn't unmount
+    /// it, so unless [`BackgroundSession::unmount`] was called first it waits for
+    /// someone else to.
+    pub fn join(mut self) -> Result<F, MountError> {
+        let thread = self.thread.take().expect("BackgroundSession joined twice");
+        thread.join().unwrap_or(Err(MountError::Session(1)))
+    }
+}
+
+impl<F> Drop for BackgroundSession<F> {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            // Fails if it's already been unmounted, which is fine
+            let _ = self.unmount();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Shuts down a [`BackgroundSession`] from any thread. It's cheap to clone.
+///
+/// Shutting down can't be done from a signal handler itself, since it takes locks
+/// and runs `fusermount`. Have the handler wake up a thread that calls
+/// [`ShutdownHandle::shutdown`] instead, which is what crates like `ctrlc` do anyway.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    /// Stops the loop and unmounts the filesystem, then waits for the requests being
+    /// handled to finish and for `destroy`. Files that are still open keep the kernel's
+    /// side of the filesystem around, so this waits for them to be closed too.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.begin()?;
+        self.shared.wait(None);
+        Ok(())
+    }
+
+    /// Same as [`ShutdownHandle::shutdown`], except that after `timeout` the kernel's
+    /// connection gets aborted, which fails everything still waiting on the filesystem.
+    /// Requests that are already being handled still get to finish.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.begin()?;
+        if !self.shared.wait(Some(timeout)) {
+            self.shared.abort()?;
+            self.shared.wait(None);
+        }
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.shared.is_finished()
+    }
+
+    fn begin(&self) -> io::Result<()> {
+        let running = match &*self.shared.exit.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(exit) => {
+                exit.exit();
+                true
+            }
+            None => false,
+        };
+
+        // The loop only notices it's been told to exit once the next request comes in,
+        // which unmounting makes happen. Someone else beating us to it is fine.
+        match fusermount::unmount(self.shared.mountpoint.as_os_str()) {
+            Err(e) if running && !self.shared.is_finished() => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+// What the session and its shutdown handles have in common
+struct Shared {
+    mountpoint: PathBuf,
+    // The connection's number under /sys/fs/fuse/connections, for aborting it
+    connection: Option<u64>,
+    // Tells the loop to stop, for as long as it's running
+    exit: Mutex<Option<Exit>>,
+    finished: Mutex<bool>,
+    finishing: Condvar,
+}
+
+impl Shared {
+    fn stopped(&self) {
+        *self.exit.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    fn finish(&self) {
+        *self.finished.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.finishing.notify_all();
+    }
+
+    fn is_finished(&self) -> bool {
+        *self.finished.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Returns whether the session finished in time
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut finished = self.finished.lock().unwrap_or_else(|e| e.into_inner());
+
+        while !*finished {
+            finished = match deadline {
+                None => self.finishing.wait(finished).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let left = match deadline.checked_duration_since(Instant::now()) {
+                        Some(left) => left,
+                        None => return false,
+                    };
+                    self.finishing
+                        .wait_timeout(finished, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+        true
+    }
+
+    #[cfg(target_os = "linux")]
+    fn abort(&self) -> io::Result<()> {
+        let connection = self
+            .connection
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "couldn't find the fuse connection"))?;
+        std::fs::write(format!("/sys/fs/fuse/connections/{connection}/abort"), "1")
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn abort(&self) -> io::Result<()> {
+        let status = std::process::Command::new("umount")
+            .arg("-f")
+            .arg(&self.mountpoint)
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("umount -f failed with {status}")))
+        }
+    }
+}
+
+// Looked up in mountinfo rather than by stat-ing the mountpoint, which would be a request
+// to a filesystem nobody's serving yet. Connections are named after their device's minor.
+#[cfg(target_os = "linux")]
+fn connection(mountpoint: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mountpoint = mountpoint.canonicalize().ok()?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+
+    // mountinfo escapes whitespace and backslashes as octal
+    let escaped: String = mountpoint
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .map(|&b| match b {
+            b' ' | b'\t' | b'\n' | b'\\' => format!("\\{b:03o}"),
+            b => (b as char).to_string(),
+        })
+        .collect();
+
+    // The newest mount on the mountpoint is ours
+    mountinfo.lines().rev().find_map(|line| {
+        let mut fields = line.split(' ');
+        let device = fields.nth(2)?;
+        let mounted_on = fields.nth(1)?;
+        let fs_type = line.split(" - ").nth(1)?.split(' ').next()?;
+
+        if mounted_on != escaped || !fs_type.starts_with("fuse") {
+            return None;
+        }
+        device.split(':').nth(1)?.parse().ok()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connection(_mountpoint: &Path) -> Option<u64> {
+    None
+}
+
+// Mounts the filesystem without serving it yet. The loop hands back the filesystem
+// and its user data once it's done with them.
+fn mount<const UNTHREADED: bool, F: FuseMain<UNTHREADED>>(
+    fs: F,
+    options: &MountOptions,
+) -> io::Result<(Mount, Owned<F>)> {
+    let operations = fs.operations();
+
+    let this = Box::into_raw(Box::new(fs));
+    let session = Liveness::new();
+    let user_data = Box::into_raw(Box::new(UserData::new(this, session.clone())));
+
+    match unsafe { Mount::open(options.to_args(true), &operations, user_data as *mut c_void) } {
+        Ok(mount) => Ok((mount, Owned { this, user_data, session })),
+        Err(e) => {
+            unsafe {
+                drop(Box::from_raw(user_data));
+                drop(Box::from_raw(this));
+            }
+            Err(e)
+        }
+    }
+}
+
+// The filesystem and the user data pointing at it, which the loop uses until it stops.
+// The session has to be ended once it does, for any poll handles that are still around.
+struct Owned<F> {
+    this: *mut F,
+    user_data: *mut UserData<F>,
+    session: Liveness,
+}
+
+// Only one thread ever touches them at a time
+unsafe impl<F: Send> Send for Owned<F> {}
+
+impl<F> Owned<F> {
+    unsafe fn into_inner(self) -> F {
+        drop(Box::from_raw(self.user_data));
+        *Box::from_raw(self.this)
+    }
+}
+
+#[cfg(feature = "pure_rust")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "pure_rust")]
+struct Mount {
+    mount: crate::kernel::Mount,
+    exiting: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "pure_rust")]
+struct Exit(Arc<AtomicBool>);
+
+#[cfg(feature = "pure_rust")]
+impl Exit {
+    fn exit(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "pure_rust")]
+impl Mount {
+    unsafe fn open(args: Vec<CString>, op: &fuse_operations, user_data: *mut c_void) -> io::Result<Self> {
+        let argv: Vec<_> = args.iter().map(|arg| arg.as_c_str()).collect();
+        Ok(Self {
+            mount: crate::kernel::Mount::new(&argv, op, user_data)?,
+            exiting: Arc::default(),
+        })
+    }
+
+    fn exit(&self) -> Exit {
+        Exit(self.exiting.clone())
+    }
+
+    // The kernel tears everything down before its loop returns, so telling it to exit
+    // afterwards is harmless, it just won't be listening
+    unsafe fn serve(self, _threaded: bool, threading: &ThreadingConfig, signals: bool, stopped: impl FnOnce()) -> c_int {
+        threading.thread_started();
+
+        let out = if signals {
+            self.mount.run_until_signalled()
+        } else {
+            self.mount.run(&self.exiting)
+        };
+        stopped();
+        out
+    }
+
+    // A loop that's already been told to exit goes straight to tearing down
+    unsafe fn close(self) {
+        self.exiting.store(true, Ordering::SeqCst);
+        self.mount.run(&self.exiting);
+    }
+}
+
+// The steps fuse_main goes through, split up so mounting can fail before the thread starts.
+#[cfg(not(feature = "pure_rust"))]
+struct Mount {
+    fuse: *mut crate::fuse,
+    #[cfg(not(feature = "fuse3"))]
+    chan: *mut crate::fuse_chan,
+    #[cfg(not(feature = "fuse3"))]
+    mountpoint: *mut std::os::raw::c_char,
+}
+
+// libfuse doesn't care which thread runs the loop
+#[cfg(not(feature = "pure_rust"))]
+unsafe impl Send for Mount {}
+
+// Only good until the loop stops, which Shared makes sure of
+#[cfg(not(feature = "pure_rust"))]
+struct Exit(*mut crate::fuse);
+
+#[cfg(not(feature = "pure_rust"))]
+unsafe impl Send for Exit {}
+
+#[cfg(not(feature = "pure_rust"))]
+impl Exit {
+    fn exit(&self) {
+        unsafe { crate::fuse_exit(self.0) };
+    }
+}
+
+// Arguments that fuse_parse_cmdline picks apart, which only live as long as `args` does.
+#[cfg(not(feature = "pure_rust"))]
+fn fuse_args(args: &[CString], argv: &mut Vec<*mut std::os::raw::c_char>) -> crate::fuse_args {
+    *argv = args.iter().map(|arg| arg.as_ptr() as *mut _).collect();
+    crate::fuse_args {
+        argc: argv.len() as c_int,
+        argv: argv.as_mut_ptr(),
+        allocated: 0,
+    }
+}
+
+#[cfg(not(any(feature = "pure_rust", feature = "fuse3")))]
+impl Mount {
+    unsafe fn open(args: Vec<CString>, op: &fuse_operations, user_data: *mut c_void) -> io::Result<Self> {
+        let mut argv = vec![];
+        let mut args = fuse_args(&args, &mut argv);
+
+        let mut mountpoint = std::ptr::null_mut();
+        if crate::fuse_parse_cmdline(&mut args, &mut mountpoint, std::ptr::null_mut(), std::ptr::null_mut()) == -1
+            || mountpoint.is_null()
+        {
+            crate::fuse_opt_free_args(&mut args);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "libfuse couldn't parse the options"));
+        }
+
+        let chan = crate::fuse_mount(mountpoint, &mut args);
+        if chan.is_null() {
+            crate::fuse_opt_free_args(&mut args);
+            libc::free(mountpoint as *mut c_void);
+            return Err(io::Error::other("libfuse couldn't mount the filesystem"));
+        }
+
+        let fuse = crate::fuse_new(chan, &mut args, op, std::mem::size_of::<fuse_operations>(), user_data);
+        crate::fuse_opt_free_args(&mut args);
+        if fuse.is_null() {
+            crate::fuse_unmount(mountpoint, chan);
+            libc::free(mountpoint as *mut c_void);
+            return Err(io::Error::other("libfuse couldn't set up the filesystem"));
+        }
+
+        Ok(Self {
+            fuse,
+            chan,
+            mountpoint,
+        })
+    }
+
+    fn exit(&self) -> Exit {
+        Exit(self.fuse)
+    }
+
+    unsafe fn serve(self, threaded: bool, threading: &ThreadingConfig, signals: bool, stopped: impl FnOnce()) -> c_int {
+        let session = crate::fuse_get_session(self.fuse);
+        let signals = signals && crate::fuse_set_signal_handlers(session) != -1;
+
+        let out = if threaded {
+            crate::workers::run(self.fuse, threading)
+        } else {
+            threading.thread_started();
+            crate::fuse_loop(self.fuse)
+        };
+        stopped();
+
+        if signals {
+            crate::fuse_remove_signal_handlers(session);
+        }
+
+        self.close();
+        out
+    }
+
+    // Unmounting first means destroy gets called with nobody left to send requests.
+    // libfuse only calls it if init got called, which it won't have if nothing was served.
+    unsafe fn close(self) {
+        crate::fuse_unmount(self.mountpoint, self.chan);
+        crate::fuse_destroy(self.fuse);
+        libc::free(self.mountpoint as *mut c_void);
+    }
+}
+
+#[cfg(all(feature = "fuse3", not(feature = "pure_rust")))]
+impl Mount {
+    unsafe fn open(args: Vec<CString>, op: &fuse_operations, user_data: *mut c_void) -> io::Result<Self> {
+        let mut argv = vec![];
+        let mut args = fuse_args(&args, &mut argv);
+
+        let mut opts = crate::fuse_cmdline_opts::default();
+        if crate::fuse_parse_cmdline(&mut args, &mut opts) != 0 || opts.mountpoint.is_null() {
+            crate::fuse_opt_free_args(&mut args);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "libfuse couldn't parse the options"));
+        }
+
+        let fuse = crate::fuse_new(&mut args, op, std::mem::size_of::<fuse_operations>(), user_data);
+        crate::fuse_opt_free_args(&mut args);
+
+        let mounted = !fuse.is_null() && crate::fuse_mount(fuse, opts.mountpoint) == 0;
+        libc::free(opts.mountpoint as *mut c_void);
+
+        if !mounted {
+            if !fuse.is_null() {
+                crate::fuse_destroy(fuse);
+            }
+            return Err(io::Error::other("libfuse couldn't mount the filesystem"));
+        }
+
+        Ok(Self { fuse })
+    }
+
+    fn exit(&self) -> Exit {
+        Exit(self.fuse)
+    }
+
+    unsafe fn serve(self, threaded: bool, threading: &ThreadingConfig, signals: bool, stopped: impl FnOnce()) -> c_int {
+        let session = crate::fuse_get_session(self.fuse);
+        let signals = signals && crate::fuse_set_signal_handlers(session) == 0;
+
+        let out = if threaded && threading.clone_fd {
+            crate::fuse_loop_mt_31(self.fuse, 1)
+        } else if threaded {
+            crate::workers::run(self.fuse, threading)
+        } else {
+            threading.thread_started();
+            crate::fuse_loop(self.fuse)
+        };
+        stopped();
+
+        if signals {
+            crate::fuse_remove_signal_handlers(session);
+        }
+
+        self.close();
+        out
+    }
+
+    unsafe fn close(self) {
+        crate::fuse_unmount(self.fuse);
+        crate::fuse_destroy(self.fuse);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileSystem, FileSystemRaw};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Default)]
+    struct Destroyed(AtomicBool);
+
+    impl FileSystem for Destroyed {
+        type FileHandle = ();
+
+        fn destroy(&self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // What mount hands libfuse, minus the mounting, which needs /dev/fuse
+    #[test]
+    fn shared_arc_gets_destroyed() {
+        let fs = Arc::new(Destroyed::default());
+
+        let session = Liveness::new();
+        let this = Box::into_raw(Box::new(fs.clone()));
+        let user_data = Box::into_raw(Box::new(UserData::new(this, session.clone())));
+        let owned = Owned { this, user_data, session };
+
+        unsafe {
+            <Arc<Destroyed> as FileSystemRaw<false>>::destroy(user_data as *mut c_void);
+            drop(owned.into_inner());
+        }
+        assert!(fs.0.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,197 @@
+//! Glue between the generated [`AsyncFileSystem`](crate::AsyncFileSystem) shims and tokio.
+//!
+//! libfuse only hands us its arguments for as long as the callback runs, so the shims
+//! copy them out with [`Detach`], spawn the handler's future, and lend the copies back
+//! to it once it's running.
+
+use std::{
+    ffi::{OsStr, OsString},
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll},
+};
+
+use tokio::runtime::Handle;
+
+/// An argument that can outlive the callback it came from.
+#[doc(hidden)]
+pub trait Detach<'a> {
+    type Owned: Send + 'static;
+
+    fn detach(self) -> Self::Owned;
+    fn attach(owned: &'a mut Self::Owned) -> Self;
+}
+
+macro_rules! detach_copy {
+    ($($ty:ty),*) => {
+        $(
+            impl<'a> Detach<'a> for $ty {
+                type Owned = $ty;
+
+                fn detach(self) -> $ty {
+                    self
+                }
+
+                fn attach(owned: &'a mut $ty) -> $ty {
+                    *owned
+                }
+            }
+        )*
+    };
+}
+
+detach_copy!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, usize, isize);
+
+impl<'a> Detach<'a> for &'a OsStr {
+    type Owned = OsString;
+
+    fn detach(self) -> OsString {
+        self.to_owned()
+    }
+
+    fn attach(owned: &'a mut OsString) -> Self {
+        owned
+    }
+}
+
+impl<'a> Detach<'a> for &'a Path {
+    type Owned = PathBuf;
+
+    fn detach(self) -> PathBuf {
+        self.to_owned()
+    }
+
+    fn attach(owned: &'a mut PathBuf) -> Self {
+        owned
+    }
+}
+
+impl<'a> Detach<'a> for &'a [u8] {
+    type Owned = Vec<u8>;
+
+    fn detach(self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn attach(owned: &'a mut Vec<u8>) -> Self {
+        owned
+    }
+}
+
+// The structs libfuse passes by pointer (fuse_file_info, stat, flock) are all plain old data
+impl<'a, T: Copy + Send + 'static> Detach<'a> for Option<&'a T> {
+    type Owned = Option<T>;
+
+    fn detach(self) -> Option<T> {
+        self.copied()
+    }
+
+    fn attach(owned: &'a mut Option<T>) -> Self {
+        owned.as_ref()
+    }
+}
+
+impl<'a, T: Copy + Send + 'static> Detach<'a> for Option<&'a mut T> {
+    type Owned = Option<T>;
+
+    fn detach(self) -> Option<T> {
+        self.copied()
+    }
+
+    fn attach(owned: &'a mut Option<T>) -> Self {
+        owned.as_mut()
+    }
+}
+
+// Poll handles get moved into the handler, since they're meant to be kept
+impl<'a> Detach<'a> for Option<crate::PollHandle> {
+    type Owned = Option<crate::PollHandle>;
+
+    fn detach(self) -> Self {
+        self
+    }
+
+    fn attach(owned: &'a mut Self) -> Self {
+        owned.take()
+    }
+}
+
+/// The filesystem and runtime a session hands to every request, which also keeps count
+/// of the requests still being worked on so the session can wait for them before it goes away.
+#[doc(hidden)]
+pub struct Tasks<F> {
+    pub fs: Arc<F>,
+    handle: Handle,
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl<F> Tasks<F> {
+    pub fn new(fs: F, handle: Handle) -> Self {
+        Self {
+            fs: Arc::new(fs),
+            handle,
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Runs a handler's future on the runtime. Panics get caught the same way
+    /// they are for every other handler.
+    pub fn spawn<Fut>(&self, operation: &'static str, exit: fn(), future: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (count, _) = &*self.running;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+
+        self.handle.spawn(Task {
+            operation,
+            exit,
+            future: Box::pin(future),
+            _running: Running(self.running.clone()),
+        });
+    }
+
+    /// Blocks until every spawned future has finished.
+    pub fn wait(&self) {
+        let (count, done) = &*self.running;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count > 0 {
+            count = done.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+// Counts as running until it's dropped, which happens even if the runtime shuts down first
+struct Running(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let (count, done) = &*self.0;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        *count -= 1;
+        if *count == 0 {
+            done.notify_all();
+        }
+    }
+}
+
+struct Task<Fut> {
+    operation: &'static str,
+    exit: fn(),
+    future: Pin<Box<Fut>>,
+    _running: Running,
+}
+
+impl<Fut: Future<Output = ()>> Future for Task<Fut> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        let future = this.future.as_mut();
+
+        // A future that panicked is done, and dropping it answers its request with EIO
+        crate::unwind::catch(this.operation, this.exit, || future.poll(cx)).unwrap_or(Poll::Ready(()))
+    }
+}
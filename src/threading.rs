@@ -0,0 +1,116 @@
+//! How a threaded filesystem's requests get spread over threads.
+
+use std::{fmt, sync::Arc};
+
+/// Sizes and sets up the pool of threads a [`FileSystem`](crate::FileSystem) gets served
+/// from. Handed to [`MountOptionsBuilder::threading`](crate::MountOptionsBuilder::threading).
+///
+/// The pool starts with one thread, and adds another whenever a request comes in and
+/// nobody's left waiting for the next one, up to `max_threads`. Threads that find more than
+/// `max_idle_threads` others waiting once they're done with a request exit.
+///
+/// ```no_run
+/// # use fuse_sys::ThreadingConfig;
+/// let threading = ThreadingConfig::new()
+///     .max_threads(64)
+///     .thread_name_prefix("db-fs")
+///     .on_thread_start(|| println!("worker started"));
+/// ```
+#[derive(Clone)]
+pub struct ThreadingConfig {
+    pub(crate) max_threads: usize,
+    pub(crate) max_idle_threads: usize,
+    pub(crate) clone_fd: bool,
+    pub(crate) thread_name_prefix: String,
+    on_thread_start: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl ThreadingConfig {
+    /// Ten threads at most, like libfuse.
+    pub fn new() -> Self {
+        Self {
+            max_threads: 10,
+            max_idle_threads: 10,
+            clone_fd: false,
+            thread_name_prefix: "fuse".to_owned(),
+            on_thread_start: None,
+        }
+    }
+
+    /// The most requests that get handled at once. Anything past that waits in the kernel.
+    pub fn max_threads(mut self, threads: usize) -> Self {
+        self.max_threads = threads;
+        self
+    }
+
+    /// How many threads can sit around waiting for requests before they start exiting.
+    pub fn max_idle_threads(mut self, threads: usize) -> Self {
+        self.max_idle_threads = threads;
+        self
+    }
+
+    /// Gives every thread its own file descriptor for `/dev/fuse`, so they don't all wake up for
+    /// each request. Only fuse3 can do this, and only from libfuse's own loop, which has its
+    /// own idea of how many threads to run. So [`MountOptionsBuilder::build`] turns it down
+    /// with fuse2, or along with any of the other settings here.
+    ///
+    /// [`MountOptionsBuilder::build`]: crate::MountOptionsBuilder::build
+    pub fn clone_fd(mut self, clone: bool) -> Self {
+        self.clone_fd = clone;
+        self
+    }
+
+    /// Threads get called this followed by `-` and a number, like `fuse-3`.
+    pub fn thread_name_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.thread_name_prefix = prefix.into();
+        self
+    }
+
+    /// Runs on every thread that handles requests, before it handles any. A good place
+    /// to set up thread locals, like a connection to a database.
+    pub fn on_thread_start(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_thread_start = Some(Arc::new(hook));
+        self
+    }
+
+    // The first setting libfuse's loop would ignore if clone_fd was on
+    pub(crate) fn ignored_by_clone_fd(&self) -> Option<&'static str> {
+        let default = Self::new();
+        if self.max_threads != default.max_threads {
+            Some("max_threads")
+        } else if self.max_idle_threads != default.max_idle_threads {
+            Some("max_idle_threads")
+        } else if self.thread_name_prefix != default.thread_name_prefix {
+            Some("thread_name_prefix")
+        } else if self.on_thread_start.is_some() {
+            Some("on_thread_start")
+        } else {
+            None
+        }
+    }
+
+    // A panicking hook gets reported like a panicking handler, and the thread carries on
+    pub(crate) fn thread_started(&self) {
+        if let Some(hook) = &self.on_thread_start {
+            crate::unwind::catch("on_thread_start", || (), || hook());
+        }
+    }
+}
+
+impl Default for ThreadingConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ThreadingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadingConfig")
+            .field("max_threads", &self.max_threads)
+            .field("max_idle_threads", &self.max_idle_threads)
+            .field("clone_fd", &self.clone_fd)
+            .field("thread_name_prefix", &self.thread_name_prefix)
+            .field("on_thread_start", &self.on_thread_start.is_some())
+            .finish()
+    }
+}
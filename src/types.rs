@@ -0,0 +1,375 @@
+//! What the high level handlers hand back, and how it gets written into
+//! the structs libfuse passed us.
+
+use std::{
+    ffi::OsString,
+    ops::BitOr,
+    os::{
+        raw::{c_char, c_int},
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{fuse_file_info, stat, statvfs, timespec};
+
+/// The attributes of a file, returned by `getattr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileAttr {
+    pub ino: u64,
+    pub size: u64,
+    /// The number of 512 byte blocks allocated to the file.
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// The file type and permissions, like `st_mode`.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub blksize: u32,
+}
+
+impl Default for FileAttr {
+    fn default() -> Self {
+        Self {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            mode: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+        }
+    }
+}
+
+fn system_time(sec: i64, nsec: i64) -> SystemTime {
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::new(sec as u64, nsec as u32)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + Duration::from_nanos(nsec as u64)
+    }
+}
+
+// Splits a time into the seconds and nanoseconds of a timespec,
+// where the nanoseconds are always positive even before the epoch.
+pub(crate) fn timespec_parts(time: SystemTime) -> (i64, i64) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos() as i64),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                nsec => (-(d.as_secs() as i64) - 1, 1_000_000_000 - nsec as i64),
+            }
+        }
+    }
+}
+
+impl From<std::fs::Metadata> for FileAttr {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        Self {
+            ino: metadata.ino(),
+            size: metadata.size(),
+            blocks: metadata.blocks(),
+            atime: system_time(metadata.atime(), metadata.atime_nsec()),
+            mtime: system_time(metadata.mtime(), metadata.mtime_nsec()),
+            ctime: system_time(metadata.ctime(), metadata.ctime_nsec()),
+            mode: metadata.mode(),
+            nlink: metadata.nlink() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: metadata.rdev(),
+            blksize: metadata.blksize() as u32,
+        }
+    }
+}
+
+impl FileAttr {
+    pub fn to_stat(&self) -> stat {
+        let mut out = stat {
+            st_ino: self.ino as _,
+            st_size: self.size as _,
+            st_blocks: self.blocks as _,
+            st_mode: self.mode as _,
+            st_nlink: self.nlink as _,
+            st_uid: self.uid as _,
+            st_gid: self.gid as _,
+            st_rdev: self.rdev as _,
+            st_blksize: self.blksize as _,
+            ..Default::default()
+        };
+
+        // macOS calls them st_atimespec and friends
+        #[cfg(target_os = "macos")]
+        let times = [
+            (&mut out.st_atimespec, self.atime),
+            (&mut out.st_mtimespec, self.mtime),
+            (&mut out.st_ctimespec, self.ctime),
+        ];
+        #[cfg(not(target_os = "macos"))]
+        let times = [
+            (&mut out.st_atim, self.atime),
+            (&mut out.st_mtim, self.mtime),
+            (&mut out.st_ctim, self.ctime),
+        ];
+
+        for (timespec, time) in times {
+            let (sec, nsec) = timespec_parts(time);
+            timespec.tv_sec = sec as _;
+            timespec.tv_nsec = nsec as _;
+        }
+
+        out
+    }
+}
+
+/// Filesystem statistics, returned by `statfs`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatFs {
+    /// The total number of `frsize` blocks.
+    pub blocks: u64,
+    pub bfree: u64,
+    /// Free blocks for unprivileged users.
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+impl StatFs {
+    pub fn to_statvfs(&self) -> statvfs {
+        statvfs {
+            f_blocks: self.blocks as _,
+            f_bfree: self.bfree as _,
+            f_bavail: self.bavail as _,
+            f_files: self.files as _,
+            f_ffree: self.ffree as _,
+            f_favail: self.ffree as _,
+            f_bsize: self.bsize as _,
+            f_namemax: self.namelen as _,
+            f_frsize: self.frsize as _,
+            ..Default::default()
+        }
+    }
+}
+
+/// What `open`, `opendir` and `create` hand back to the kernel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenReply<H> {
+    /// Kept around until the file is released, and handed to every call on it in between.
+    pub handle: H,
+    /// Skip the page cache, so reads and writes can return any number of bytes.
+    pub direct_io: bool,
+    /// Don't throw away cached data from earlier opens.
+    pub keep_cache: bool,
+    pub nonseekable: bool,
+}
+
+impl<H> OpenReply<H> {
+    pub fn new(handle: H) -> Self {
+        Self {
+            handle,
+            direct_io: false,
+            keep_cache: false,
+            nonseekable: false,
+        }
+    }
+
+    // The handle lives in a box that fh points at until release takes it back out
+    pub(crate) fn write_to(self, info: &mut fuse_file_info) {
+        info.fh = Box::into_raw(Box::new(self.handle)) as u64;
+        info.set_direct_io(self.direct_io as _);
+        info.set_keep_cache(self.keep_cache as _);
+        info.set_nonseekable(self.nonseekable as _);
+    }
+}
+
+impl<H> From<H> for OpenReply<H> {
+    fn from(handle: H) -> Self {
+        Self::new(handle)
+    }
+}
+
+/// A time `utimens` should set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeOrNow {
+    SpecificTime(SystemTime),
+    /// Whatever the time is when it gets set, like `UTIME_NOW`.
+    Now,
+}
+
+impl From<SystemTime> for TimeOrNow {
+    fn from(time: SystemTime) -> Self {
+        TimeOrNow::SpecificTime(time)
+    }
+}
+
+impl TimeOrNow {
+    /// The time this stands for, reading the clock if it's `Now`.
+    pub fn resolve(self) -> SystemTime {
+        match self {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => SystemTime::now(),
+        }
+    }
+
+    // None is UTIME_OMIT, meaning the time shouldn't be touched
+    fn from_timespec(time: &timespec) -> Option<Self> {
+        if time.tv_nsec == libc::UTIME_OMIT as _ {
+            None
+        } else if time.tv_nsec == libc::UTIME_NOW as _ {
+            Some(TimeOrNow::Now)
+        } else {
+            Some(TimeOrNow::SpecificTime(system_time(
+                time.tv_sec as _,
+                time.tv_nsec as _,
+            )))
+        }
+    }
+}
+
+// utimens gets the access and modification times as a timespec[2], or null for both being now
+pub(crate) unsafe fn utimens_times(tv: *const timespec) -> (Option<TimeOrNow>, Option<TimeOrNow>) {
+    if tv.is_null() {
+        return (Some(TimeOrNow::Now), Some(TimeOrNow::Now));
+    }
+
+    (
+        TimeOrNow::from_timespec(&*tv),
+        TimeOrNow::from_timespec(&*tv.add(1)),
+    )
+}
+
+// libfuse wants readlink to fill a nul terminated buffer, cutting the link short if it has to.
+pub(crate) unsafe fn write_link(link: &Path, buf: *mut c_char, size: usize) {
+    if buf.is_null() || size == 0 {
+        return;
+    }
+
+    let link = link.as_os_str().as_bytes();
+    let len = link.len().min(size - 1);
+    std::ptr::copy_nonoverlapping(link.as_ptr() as *const c_char, buf, len);
+    *buf.add(len) = 0;
+}
+
+/// What `setxattr` should do about an attribute that already exists, or doesn't.
+/// Empty means it gets created or replaced, whichever it takes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XattrFlags(c_int);
+
+impl XattrFlags {
+    /// Fail with `EEXIST` if the attribute already exists.
+    pub const CREATE: Self = Self(libc::XATTR_CREATE);
+    /// Fail with `ENODATA` if the attribute doesn't exist yet.
+    pub const REPLACE: Self = Self(libc::XATTR_REPLACE);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Keeps any bits that aren't `CREATE` or `REPLACE`, like macOS's `XATTR_NOFOLLOW`.
+    pub fn from_raw(flags: c_int) -> Self {
+        Self(flags)
+    }
+
+    pub fn raw(self) -> c_int {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for XattrFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+// getxattr and listxattr get called with a size of 0 to ask how big the value is,
+// and have to fail with ERANGE if they're given a buffer that's too small for it.
+pub(crate) unsafe fn write_xattr(value: &[u8], buf: *mut c_char, size: usize) -> c_int {
+    if value.len() > c_int::MAX as usize {
+        return -libc::E2BIG;
+    }
+    if size == 0 {
+        return value.len() as c_int;
+    }
+    if value.len() > size {
+        return -libc::ERANGE;
+    }
+
+    std::ptr::copy_nonoverlapping(value.as_ptr() as *const c_char, buf, value.len());
+    value.len() as c_int
+}
+
+// listxattr's names go one after the other, each one nul terminated
+pub(crate) unsafe fn write_xattr_names(names: &[OsString], buf: *mut c_char, size: usize) -> c_int {
+    let mut list = Vec::with_capacity(names.iter().map(|name| name.len() + 1).sum());
+    for name in names {
+        let name = name.as_bytes();
+        if name.is_empty() || name.contains(&0) {
+            return -libc::EINVAL;
+        }
+        list.extend_from_slice(name);
+        list.push(0);
+    }
+
+    write_xattr(&list, buf, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xattr_values() {
+        let mut buf = [0 as c_char; 8];
+        unsafe {
+            // A size of 0 only asks how big a buffer it needs
+            assert_eq!(write_xattr(b"value", std::ptr::null_mut(), 0), 5);
+            assert_eq!(write_xattr(b"value", buf.as_mut_ptr(), 4), -libc::ERANGE);
+            assert_eq!(write_xattr(b"value", buf.as_mut_ptr(), buf.len()), 5);
+        }
+        assert_eq!(&buf[..5], b"value".map(|b| b as c_char));
+    }
+
+    #[test]
+    fn xattr_names() {
+        let names = [OsString::from("user.a"), OsString::from("user.bc")];
+        let mut buf = [0 as c_char; 32];
+        unsafe {
+            assert_eq!(write_xattr_names(&names, std::ptr::null_mut(), 0), 15);
+            assert_eq!(write_xattr_names(&names, buf.as_mut_ptr(), 14), -libc::ERANGE);
+            assert_eq!(write_xattr_names(&names, buf.as_mut_ptr(), buf.len()), 15);
+            assert_eq!(write_xattr_names(&[], buf.as_mut_ptr(), buf.len()), 0);
+
+            assert_eq!(write_xattr_names(&[OsString::new()], buf.as_mut_ptr(), buf.len()), -libc::EINVAL);
+            assert_eq!(write_xattr_names(&[OsString::from("us\0er.a")], std::ptr::null_mut(), 0), -libc::EINVAL);
+        }
+        assert_eq!(&buf[..15], b"user.a\0user.bc\0".map(|b| b as c_char));
+    }
+
+    #[test]
+    fn xattr_flags() {
+        let flags = XattrFlags::CREATE | XattrFlags::REPLACE;
+        assert!(flags.contains(XattrFlags::CREATE) && flags.contains(XattrFlags::REPLACE));
+        assert!(!XattrFlags::empty().contains(XattrFlags::CREATE));
+        assert_eq!(XattrFlags::from_raw(libc::XATTR_CREATE), XattrFlags::CREATE);
+    }
+}
@@ -0,0 +1,84 @@
+//! Keeps panics in filesystem code from unwinding into libfuse.
+//!
+//! Every callback that libfuse makes into the generated code runs through [`catch`].
+//! A panic fails the request with `EIO`, gets reported to the panic hook,
+//! and (if [`set_exit_on_panic`] is on) stops the filesystem.
+
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+/// What a panic hook gets told about a handler that panicked.
+pub struct PanicReport<'a> {
+    /// The fuse operation that panicked, like `read` or `getattr`.
+    pub operation: &'static str,
+    pub payload: &'a (dyn Any + Send),
+}
+
+impl PanicReport<'_> {
+    /// The panic's message, if it was given one.
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&'static str>() {
+            Some(message) => Some(message),
+            None => self.payload.downcast_ref::<String>().map(|s| s.as_str()),
+        }
+    }
+}
+
+type PanicHook = Box<dyn Fn(&PanicReport) + Send + Sync>;
+
+static HOOK: RwLock<Option<PanicHook>> = RwLock::new(None);
+static EXIT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Replaces what happens when a handler panics. By default the panic gets printed to stderr.
+///
+/// This runs after the panic has been caught, on top of whatever `std::panic::set_hook` does.
+pub fn set_panic_hook(hook: impl Fn(&PanicReport) + Send + Sync + 'static) {
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+}
+
+/// Goes back to printing panics to stderr.
+pub fn reset_panic_hook() {
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Whether a panicking handler should make the filesystem exit its loop and unmount.
+/// Off by default, in which case only the request that panicked fails.
+pub fn set_exit_on_panic(exit: bool) {
+    EXIT_ON_PANIC.store(exit, Ordering::SeqCst);
+}
+
+fn report(operation: &'static str, payload: &(dyn Any + Send)) {
+    let report = PanicReport { operation, payload };
+
+    match &*HOOK.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(hook) => hook(&report),
+        None => eprintln!(
+            "fuse-sys: {operation} panicked: {}",
+            report.message().unwrap_or("Box<dyn Any>")
+        ),
+    }
+}
+
+/// Runs `f`, returning `None` if it panics. `exit` gets called to stop
+/// the filesystem if it panicked and [`set_exit_on_panic`] is on.
+#[doc(hidden)]
+pub fn catch<R>(operation: &'static str, exit: impl FnOnce(), f: impl FnOnce() -> R) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(out) => Some(out),
+        Err(payload) => {
+            // A panicking hook would land us right back where we started
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| report(operation, &*payload)));
+
+            if EXIT_ON_PANIC.load(Ordering::SeqCst) {
+                exit();
+            }
+            None
+        }
+    }
+}
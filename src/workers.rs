@@ -0,0 +1,290 @@
+//! The loop threaded filesystems get served from, in place of fuse_loop_mt, which
+//! can't be told how many threads to use or what to do with them.
+//!
+//! Every thread polls `/dev/fuse` along with a socket that gets written to once it's
+//! time to stop, so that nobody's left stuck in a read that'll never return.
+
+use std::{
+    io::{self, Write},
+    os::{
+        raw::c_int,
+        unix::{io::AsRawFd, net::UnixStream},
+    },
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+use crate::ThreadingConfig;
+
+/// Serves `fuse` from a pool of threads until its session exits, returning 0 or
+/// the error reading a request failed with.
+pub(crate) unsafe fn run(fuse: *mut crate::fuse, config: &ThreadingConfig) -> c_int {
+    let session = crate::fuse_get_session(fuse);
+    #[cfg(not(feature = "fuse3"))]
+    let chan = crate::fuse_session_next_chan(session, std::ptr::null_mut());
+    #[cfg(not(feature = "fuse3"))]
+    let fd = crate::fuse_chan_fd(chan);
+    #[cfg(feature = "fuse3")]
+    let fd = crate::fuse_session_fd(session);
+
+    let (stop, stopped) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => return fail(e),
+    };
+
+    // Every thread polls the same fd, and the ones that lose the race for a request
+    // shouldn't block reading it
+    let flags = libc::fcntl(fd, libc::F_GETFL);
+    if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+        return fail(io::Error::last_os_error());
+    }
+
+    // Forgets inodes for -o remember, like fuse_loop_mt does
+    if crate::fuse_start_cleanup_thread(fuse) != 0 {
+        libc::fcntl(fd, libc::F_SETFL, flags);
+        return -1;
+    }
+
+    let pool = Arc::new(Pool {
+        session,
+        #[cfg(not(feature = "fuse3"))]
+        chan,
+        fd,
+        config: config.clone(),
+        stop,
+        stopped,
+        state: Mutex::new(State::default()),
+        finished: Condvar::new(),
+    });
+
+    let spawned = pool.spawn(&mut pool.lock());
+    let out = match spawned {
+        Ok(()) => {
+            pool.wait();
+            pool.lock().error
+        }
+        Err(e) => fail(e),
+    };
+
+    crate::fuse_stop_cleanup_thread(fuse);
+    libc::fcntl(fd, libc::F_SETFL, flags);
+
+    out
+}
+
+fn fail(e: io::Error) -> c_int {
+    eprintln!("fuse: couldn't start serving requests: {e}");
+    -1
+}
+
+struct Pool {
+    session: *mut crate::fuse_session,
+    #[cfg(not(feature = "fuse3"))]
+    chan: *mut crate::fuse_chan,
+    fd: c_int,
+    config: ThreadingConfig,
+    stop: UnixStream,
+    stopped: UnixStream,
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+// libfuse's sessions are made to be shared between threads
+unsafe impl Send for Pool {}
+unsafe impl Sync for Pool {}
+
+#[derive(Default)]
+struct State {
+    threads: usize,
+    // Threads waiting for a request
+    idle: usize,
+    // Every thread that's ever been started, for naming them
+    started: usize,
+    error: c_int,
+}
+
+impl Pool {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn spawn(self: &Arc<Self>, state: &mut State) -> io::Result<()> {
+        let name = format!("{}-{}", self.config.thread_name_prefix, state.started);
+        let pool = self.clone();
+        thread::Builder::new().name(name).spawn(move || pool.work())?;
+
+        state.threads += 1;
+        state.started += 1;
+        Ok(())
+    }
+
+    fn work(self: Arc<Self>) {
+        self.config.thread_started();
+        let mut buf = Buffer::new(&self);
+
+        // Whether everyone should stop, or just this thread because there's plenty of others
+        let stop = loop {
+            if unsafe { crate::fuse_session_exited(self.session) } != 0 {
+                break true;
+            }
+
+            self.lock().idle += 1;
+            let ready = self.poll();
+            self.lock().idle -= 1;
+
+            match ready {
+                Ok(true) => {}
+                Ok(false) => break true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.lock().error = -e.raw_os_error().unwrap_or(libc::EIO);
+                    break true;
+                }
+            }
+
+            let res = unsafe { buf.receive() };
+            if res == -libc::EINTR || res == -libc::EAGAIN {
+                continue;
+            }
+            // Reading 0 means the filesystem got unmounted
+            if res <= 0 {
+                self.lock().error = res;
+                break true;
+            }
+
+            {
+                let mut state = self.lock();
+                if state.idle == 0 && state.threads < self.config.max_threads {
+                    // Not getting another thread only means requests have to wait a bit
+                    let _ = self.spawn(&mut state);
+                }
+            }
+
+            unsafe { buf.process() };
+
+            if self.lock().idle >= self.config.max_idle_threads.max(1) {
+                break false;
+            }
+        };
+
+        if stop {
+            self.stop();
+        }
+
+        let mut state = self.lock();
+        state.threads -= 1;
+        if state.threads == 0 {
+            self.finished.notify_all();
+        }
+    }
+
+    // Waits for a request, returning false if it's time to stop instead
+    fn poll(&self) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.stopped.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fds[1].revents == 0)
+    }
+
+    // Nothing ever reads the socket, so it stays readable for every thread
+    fn stop(&self) {
+        unsafe { crate::fuse_session_exit(self.session) };
+        let _ = (&self.stop).write(&[0]);
+    }
+
+    // Waits for every thread to exit. The libfuse signal handlers only tell the session to
+    // exit, so when one interrupts this thread it passes that on to the others.
+    fn wait(&self) {
+        let mut fds = [libc::pollfd {
+            fd: self.stopped.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        while unsafe { libc::poll(fds.as_mut_ptr(), 1, -1) } == -1 {
+            if unsafe { crate::fuse_session_exited(self.session) } != 0 {
+                self.stop();
+            }
+        }
+
+        let mut state = self.lock();
+        while state.threads > 0 {
+            state = self.finished.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+// What a thread reads requests into, over and over
+struct Buffer<'a> {
+    pool: &'a Pool,
+    buf: crate::fuse_buf,
+    #[cfg(not(feature = "fuse3"))]
+    chan: *mut crate::fuse_chan,
+    #[cfg(not(feature = "fuse3"))]
+    mem: Vec<u8>,
+}
+
+#[cfg(not(feature = "fuse3"))]
+impl<'a> Buffer<'a> {
+    fn new(pool: &'a Pool) -> Self {
+        let size = unsafe { crate::fuse_chan_bufsize(pool.chan) };
+        Self {
+            pool,
+            buf: crate::fuse_buf::default(),
+            chan: pool.chan,
+            mem: vec![0; size],
+        }
+    }
+
+    unsafe fn receive(&mut self) -> c_int {
+        self.buf = crate::fuse_buf {
+            mem: self.mem.as_mut_ptr() as *mut _,
+            size: self.mem.len(),
+            ..Default::default()
+        };
+        self.chan = self.pool.chan;
+        crate::fuse_session_receive_buf(self.pool.session, &mut self.buf, &mut self.chan)
+    }
+
+    unsafe fn process(&self) {
+        crate::fuse_session_process_buf(self.pool.session, &self.buf, self.chan);
+    }
+}
+
+// fuse3 allocates the memory itself the first time around
+#[cfg(feature = "fuse3")]
+impl<'a> Buffer<'a> {
+    fn new(pool: &'a Pool) -> Self {
+        Self {
+            pool,
+            buf: crate::fuse_buf::default(),
+        }
+    }
+
+    unsafe fn receive(&mut self) -> c_int {
+        crate::fuse_session_receive_buf(self.pool.session, &mut self.buf)
+    }
+
+    unsafe fn process(&self) {
+        crate::fuse_session_process_buf(self.pool.session, &self.buf);
+    }
+}
+
+#[cfg(feature = "fuse3")]
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.buf.mem) };
+    }
+}
@@ -1,7 +1,10 @@
-#ifdef __APPLE__
+#if defined(FUSE_SYS_FUSE3)
+#define FUSE_USE_VERSION 31
+#elif defined(__APPLE__)
 #define FUSE_USE_VERSION 26
 #endif
 
 #define _FILE_OFFSET_BITS  64
 
 #include <fuse.h>
+#include <fuse_lowlevel.h>